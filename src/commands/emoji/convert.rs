@@ -0,0 +1,312 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::imageops::FilterType;
+use image::{AnimationDecoder, Delay, Frame, ImageFormat, ImageReader, RgbaImage};
+use std::io::Cursor;
+use std::time::Duration;
+
+/// The formats discord takes for emoji as they are.  Anything else is converted to PNG.
+const UPLOADABLE_FORMATS: [ImageFormat; 3] =
+    [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif];
+
+/// The biggest emoji discord takes.
+const MAX_EMOJI_BYTES: usize = 256 * 1024;
+/// Discord shows emoji at 128x128 at most, so anything bigger is wasted bytes.
+const MAX_EMOJI_SIDE: u32 = 128;
+/// The sizes an image that's still too big is shrunk to, one after another, until it fits.
+const FALLBACK_SIDES: [u32; 5] = [128, 96, 64, 48, 32];
+/// How hard the GIF encoder works on its palettes, from 1 (best) to 30 (fastest).
+const GIF_ENCODER_SPEED: i32 = 10;
+
+/// An image ready to upload as an emoji.
+pub(crate) struct EmojiImage {
+    bytes: Vec<u8>,
+    pub(crate) format: ImageFormat,
+    pub(crate) animated: bool,
+}
+
+impl EmojiImage {
+    /// Work out what an image is from its magic bytes, whatever its file is called.  Formats
+    /// discord doesn't take are converted to PNG, which keeps only the first frame of an animated
+    /// WebP.
+    pub(crate) fn from_bytes(bytes: Vec<u8>) -> Result<EmojiImage> {
+        let format = detect(&bytes)?;
+        if UPLOADABLE_FORMATS.contains(&format) {
+            let animated = format == ImageFormat::Gif && is_animated_gif(&bytes)?;
+            return Ok(EmojiImage {
+                bytes,
+                format,
+                animated,
+            });
+        }
+
+        let image = image::load_from_memory_with_format(&bytes, format)
+            .with_context(|| format!("couldn't read the {format:?} image"))?;
+        let mut png = vec![];
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .with_context(|| format!("couldn't convert the {format:?} image to PNG"))?;
+        info!("Converted a {format:?} emoji to PNG.");
+        Ok(EmojiImage {
+            bytes: png,
+            format: ImageFormat::Png,
+            animated: false,
+        })
+    }
+
+    /// Make the image fit discord's limits: at most 128x128 and 256 KiB.  A static image that's too
+    /// big is scaled down and saved as PNG.  An animated one keeps every frame if it can, and
+    /// drops every other frame if it has to; either way it shrinks until it fits or gets too small
+    /// to be worth uploading.
+    pub(crate) fn fit(self) -> Result<EmojiImage> {
+        let (width, height) = ImageReader::with_format(Cursor::new(&self.bytes), self.format)
+            .into_dimensions()
+            .context("couldn't read the image's size")?;
+        if self.bytes.len() <= MAX_EMOJI_BYTES && width.max(height) <= MAX_EMOJI_SIDE {
+            return Ok(self);
+        }
+
+        let original = (width, height, self.bytes.len());
+        let fitted = match self.animated {
+            true => fit_animated(&self.bytes)?,
+            false => fit_static(&self.bytes, self.format)?,
+        };
+        info!(
+            ?original,
+            bytes = fitted.bytes.len(),
+            animated = fitted.animated,
+            "Shrunk an emoji to fit."
+        );
+        Ok(fitted)
+    }
+
+    /// The image as a data URI, the way discord wants it.
+    pub(crate) fn data_uri(&self) -> String {
+        format!(
+            "data:{};base64,{}",
+            self.format.to_mime_type(),
+            general_purpose::STANDARD.encode(&self.bytes)
+        )
+    }
+}
+
+fn fit_static(bytes: &[u8], format: ImageFormat) -> Result<EmojiImage> {
+    let image = image::load_from_memory_with_format(bytes, format)
+        .with_context(|| format!("couldn't read the {format:?} image"))?;
+    // an image that's only too big in bytes isn't scaled up first.
+    let largest = image.width().max(image.height());
+    for side in FALLBACK_SIDES {
+        let mut png = vec![];
+        image
+            .resize(side.min(largest), side.min(largest), FilterType::Lanczos3)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .context("couldn't save the shrunk image")?;
+        if png.len() <= MAX_EMOJI_BYTES {
+            return Ok(EmojiImage {
+                bytes: png,
+                format: ImageFormat::Png,
+                animated: false,
+            });
+        }
+    }
+    bail!(
+        "it's too big for an emoji even at {0}x{0}",
+        FALLBACK_SIDES[FALLBACK_SIDES.len() - 1]
+    )
+}
+
+fn fit_animated(bytes: &[u8]) -> Result<EmojiImage> {
+    // frames are shrunk as they're decoded, so a long GIF doesn't sit in memory at full size.
+    let frames = GifDecoder::new(Cursor::new(bytes))
+        .context("couldn't read the GIF")?
+        .into_frames()
+        .map(|frame| {
+            frame.map(|f| {
+                let delay = f.delay();
+                Frame::from_parts(scale_down(f.buffer(), MAX_EMOJI_SIDE), 0, 0, delay)
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .context("couldn't read the GIF's frames")?;
+    for side in FALLBACK_SIDES {
+        for step in [1, 2] {
+            let gif = encode_gif(&frames, side, step)?;
+            if gif.len() <= MAX_EMOJI_BYTES {
+                return Ok(EmojiImage {
+                    bytes: gif,
+                    format: ImageFormat::Gif,
+                    animated: true,
+                });
+            }
+        }
+    }
+    bail!(
+        "it's too big for an animated emoji even at {0}x{0} with half its frames",
+        FALLBACK_SIDES[FALLBACK_SIDES.len() - 1]
+    )
+}
+
+/// Encode every `step`th frame, scaled to fit in `side`x`side`, as a looping GIF.  A kept frame
+/// stays up for as long as the frames dropped after it would have, so the timing doesn't change.
+fn encode_gif(frames: &[Frame], side: u32, step: usize) -> Result<Vec<u8>> {
+    let mut gif = vec![];
+    let mut encoder = GifEncoder::new_with_speed(&mut gif, GIF_ENCODER_SPEED);
+    encoder.set_repeat(Repeat::Infinite)?;
+    for group in frames.chunks(step) {
+        let delay: Duration = group.iter().map(|f| Duration::from(f.delay())).sum();
+        encoder
+            .encode_frame(Frame::from_parts(
+                scale_down(group[0].buffer(), side),
+                0,
+                0,
+                Delay::from_saturating_duration(delay),
+            ))
+            .context("couldn't save the shrunk GIF")?;
+    }
+    drop(encoder);
+    Ok(gif)
+}
+
+/// The format of an image the bot can read, from its magic bytes.
+pub(crate) fn detect(bytes: &[u8]) -> Result<ImageFormat> {
+    match image::guess_format(bytes) {
+        Ok(format) if format.reading_enabled() => Ok(format),
+        _ => bail!("it isn't an image the bot can read"),
+    }
+}
+
+/// Scale a frame down to fit in `side`x`side`, keeping its shape.  Smaller frames are left alone.
+fn scale_down(buffer: &RgbaImage, side: u32) -> RgbaImage {
+    let largest = buffer.width().max(buffer.height());
+    if largest <= side {
+        return buffer.clone();
+    }
+    let scale = |length: u32| ((length as u64 * side as u64 / largest as u64) as u32).max(1);
+    image::imageops::resize(
+        buffer,
+        scale(buffer.width()),
+        scale(buffer.height()),
+        FilterType::Triangle,
+    )
+}
+
+/// Whether a GIF has more than one frame.  Only decodes as far as the second frame.
+fn is_animated_gif(bytes: &[u8]) -> Result<bool> {
+    let decoder = GifDecoder::new(Cursor::new(bytes)).context("couldn't read the GIF")?;
+    let frames = decoder
+        .into_frames()
+        .take(2)
+        .collect::<Result<Vec<_>, _>>()
+        .context("couldn't read the GIF's frames")?;
+    Ok(frames.len() > 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::RgbImage;
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = vec![];
+        RgbImage::new(4, 4)
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    fn gif(frames: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder
+            .encode_frames((0..frames).map(|_| Frame::new(RgbaImage::new(4, 4))))
+            .unwrap();
+        drop(encoder);
+        bytes
+    }
+
+    #[test]
+    fn uploadable_formats_are_left_alone() {
+        let png = encode(ImageFormat::Png);
+        let image = EmojiImage::from_bytes(png.clone()).unwrap();
+        assert_eq!(image.format, ImageFormat::Png);
+        assert_eq!(image.bytes, png);
+        assert!(image.data_uri().starts_with("data:image/png;base64,"));
+
+        let jpeg = EmojiImage::from_bytes(encode(ImageFormat::Jpeg)).unwrap();
+        assert!(jpeg.data_uri().starts_with("data:image/jpeg;base64,"));
+    }
+
+    #[test]
+    fn other_formats_are_converted_to_png() {
+        for format in [ImageFormat::WebP, ImageFormat::Bmp] {
+            let image = EmojiImage::from_bytes(encode(format)).unwrap();
+            assert_eq!(image.format, ImageFormat::Png);
+            assert_eq!(image::guess_format(&image.bytes).unwrap(), ImageFormat::Png);
+        }
+    }
+
+    #[test]
+    fn only_gifs_with_several_frames_are_animated() {
+        let animated = EmojiImage::from_bytes(gif(3)).unwrap();
+        assert!(animated.animated);
+        assert!(animated.data_uri().starts_with("data:image/gif;base64,"));
+        assert!(!EmojiImage::from_bytes(gif(1)).unwrap().animated);
+    }
+
+    #[test]
+    fn small_images_fit_as_they_are() {
+        let png = encode(ImageFormat::Png);
+        let image = EmojiImage::from_bytes(png.clone()).unwrap().fit().unwrap();
+        assert_eq!(image.bytes, png);
+    }
+
+    #[test]
+    fn big_images_are_shrunk_to_fit() {
+        // noise doesn't compress, so a 512x512 PNG of it is about a megabyte.
+        let mut seed = 1u32;
+        let noise = RgbaImage::from_fn(512, 512, |_, _| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            image::Rgba(seed.to_le_bytes())
+        });
+        let mut png = vec![];
+        noise
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        assert!(png.len() > MAX_EMOJI_BYTES);
+
+        let image = EmojiImage::from_bytes(png).unwrap().fit().unwrap();
+        assert!(image.bytes.len() <= MAX_EMOJI_BYTES);
+        let shrunk = image::load_from_memory(&image.bytes).unwrap();
+        assert_eq!((shrunk.width(), shrunk.height()), (128, 128));
+    }
+
+    #[test]
+    fn big_animations_stay_animated() {
+        let mut bytes = vec![];
+        let mut encoder = GifEncoder::new_with_speed(&mut bytes, 30);
+        encoder
+            .encode_frames((0..3).map(|i| {
+                let frame = RgbaImage::from_pixel(300, 150, image::Rgba([i * 80, 0, 0, 255]));
+                Frame::from_parts(frame, 0, 0, Delay::from_numer_denom_ms(100, 1))
+            }))
+            .unwrap();
+        drop(encoder);
+
+        let image = EmojiImage::from_bytes(bytes).unwrap().fit().unwrap();
+        assert!(image.animated);
+        let frames = GifDecoder::new(Cursor::new(&image.bytes))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].buffer().dimensions(), (128, 64));
+    }
+
+    #[test]
+    fn anything_else_is_refused() {
+        assert!(EmojiImage::from_bytes(b"not an image at all".to_vec()).is_err());
+    }
+}
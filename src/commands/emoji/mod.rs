@@ -1,4 +1,6 @@
 use crate::commands::err_response;
+use crate::models::archive::ARCHIVE_PREFIX;
+use crate::models::discord_error::DiscordError;
 use meilisearch_sdk::client::Client as meili;
 use s3::creds::Credentials;
 use s3::{Bucket, Region};
@@ -8,13 +10,19 @@ use serenity::model::application::interaction::application_command::{
     ApplicationCommandInteraction, CommandDataOption,
 };
 use serenity::model::application::interaction::autocomplete::*;
+use serenity::model::guild::Emoji;
+use serenity::model::id::GuildId;
 use serenity::model::prelude::interaction::InteractionResponseType;
 use serenity::prelude::*;
 
-use anyhow::bail;
+use anyhow::{bail, Context as _};
+use convert::EmojiImage;
 
 use std::env;
 
+mod convert;
+pub mod upload;
+
 pub async fn do_emoji(ctx: &Context, command: ApplicationCommandInteraction) {
     let guild = match command.guild_id {
         Some(g) => g,
@@ -23,139 +31,242 @@ pub async fn do_emoji(ctx: &Context, command: ApplicationCommandInteraction) {
             return;
         }
     };
-    let emoji_option: Vec<&CommandDataOption> = command
-        .data
-        .options
-        .iter()
-        .filter(|opt| opt.name == "emoji")
-        .collect();
-    let emoji_name = match &emoji_option[0].value {
-        Some(s) => s,
-        None => {
-            error!("Did not receive an emoji name.");
-            return;
-        }
-    };
-    let emoji_str = emoji_name.to_string();
-
-    if emoji_str.len() < 2 || emoji_str.len() >= 32 {
-        err_response(
-            ctx,
-            &command,
-            "emoji name must be between 2 and 32 characters long!",
-        )
-        .await;
-        error!("emoji name specified failed the length check.");
+    let emoji_name = option_str(&command.data.options, "emoji");
+    let variant = option_str(&command.data.options, "variant");
+    let name_override = option_str(&command.data.options, "name");
+    let Some(emoji_name) = emoji_name else {
+        error!("Did not receive an emoji name.");
         return;
-    }
+    };
+    let guild_name = name_override.unwrap_or(emoji_name);
 
-    let s3_endpoint = env::var("EMOJI_S3_ENDPOINT").ok();
-    let s3_bucket = env::var("EMOJI_S3_BUCKET").ok();
-    if s3_endpoint.is_none() {
-        err_response(
-            ctx,
-            &command,
-            "bot is misconfigured: missing s3 endpoint def",
-        )
-        .await;
-        error!("need an s3 endpoint for emojis");
+    // the emoji's name picks its folder in the bucket, which holds more than emoji.
+    if !is_library_name(emoji_name) {
+        err_response(ctx, &command, &format!("emoji {emoji_name} not found!")).await;
         return;
     }
-    if s3_bucket.is_none() {
-        err_response(ctx, &command, "bot is misconfigured: missing s3 bucket def").await;
-        error!("need a bucket name for emojis");
+    if !is_valid_emoji_name(guild_name) {
+        err_response(ctx, &command, EMOJI_NAME_RULES).await;
+        error!("emoji name specified failed the length check.");
         return;
     }
 
-    let bucket = Bucket::new(
-        &s3_bucket.unwrap(),
-        Region::Custom {
-            region: "us-east-1".to_owned(),
-            endpoint: s3_endpoint.unwrap(),
-        },
-        Credentials::default().unwrap(),
-    )
-    .unwrap()
-    .with_path_style();
+    let bucket = match emoji_bucket() {
+        Ok(bucket) => bucket,
+        Err(e) => {
+            err_response(ctx, &command, &format!("bot is misconfigured: {e:#}")).await;
+            error!("{e:#}");
+            return;
+        }
+    };
 
-    let file_list = match bucket
-        .list(
-            format!("{}/", emoji_name.as_str().unwrap()),
-            Some("/".to_owned()),
-        )
+    // fetching and shrinking the image can take longer than discord waits for a response.
+    if let Err(e) = command
+        .create_interaction_response(&ctx.http, |resp| {
+            resp.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        })
         .await
     {
-        Ok(s) => s,
+        error!("Unable to defer response to command: {}", e);
+        return;
+    }
+    let reply = match import(ctx, guild, &bucket, emoji_name, variant, guild_name).await {
+        Ok(emoji) => added(&emoji),
         Err(e) => {
-            err_response(
-                ctx,
-                &command,
-                "couldn't list s3 contents. maybe wrong bucket or endpoint.",
-            )
-            .await;
-            error!("{}", e);
-            return;
+            error!("Could not import emoji {emoji_name}: {e:#}");
+            format!("**error**: {e:#}")
         }
     };
-    if file_list.is_empty() {
-        err_response(
-            ctx,
-            &command,
-            "couldn't list s3 contents. maybe wrong bucket or endpoint.",
-        )
-        .await;
-        error!("emoji not found.");
-        return;
+    if let Err(e) = command
+        .edit_original_interaction_response(&ctx.http, |resp| resp.content(reply))
+        .await
+    {
+        error!("Unable to send response to command: {}", e);
     }
-    if file_list[0].contents.is_empty() {
-        err_response(
-            ctx,
-            &command,
-            format!("emoji {} not found!", emoji_name).as_str(),
-        )
-        .await;
-        error!("emoji not found.");
-        return;
+}
+
+/// Fetch one of an emoji's images from the library and add it to the server as `guild_name`.
+async fn import(
+    ctx: &Context,
+    guild: GuildId,
+    bucket: &Bucket,
+    emoji_name: &str,
+    variant: Option<&str>,
+    guild_name: &str,
+) -> anyhow::Result<Emoji> {
+    let files = variants(bucket, emoji_name)
+        .await
+        .context("couldn't list s3 contents, maybe wrong bucket or endpoint")?;
+    if files.is_empty() {
+        bail!("emoji {emoji_name} not found!");
     }
+    // the first file is the default; a variant has to be one of the emoji's own files.
+    let file = match variant {
+        Some(variant) => match files.iter().find(|(file, _)| file == variant) {
+            Some((file, _)) => file,
+            None => bail!("emoji {emoji_name} has no variant {variant}"),
+        },
+        None => &files[0].0,
+    };
+
+    let image_data = bucket
+        .get_object(format!("{emoji_name}/{file}"))
+        .await
+        .context("couldn't fetch the emoji from s3")?;
+    install_emoji(ctx, guild, guild_name, image_data.to_vec())
+        .await
+        .context("couldn't add emoji")
+}
+
+/// A string option's value, if it was given.
+fn option_str<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a str> {
+    options
+        .iter()
+        .find(|opt| opt.name == name)
+        .and_then(|opt| opt.value.as_ref())
+        .and_then(Value::as_str)
+}
+
+const EMOJI_NAME_RULES: &str = "emoji names are 2 to 32 letters, numbers, - and _";
+
+/// Whether discord, and the search index, will take `name` for an emoji.  Discord only takes
+/// ASCII letters, numbers and `_`; hyphens are turned into underscores when it's installed.
+fn is_valid_emoji_name(name: &str) -> bool {
+    (2..=32).contains(&name.chars().count())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether `name` can be an emoji in the library, and so a folder in the emoji bucket.  The
+/// sweeper's message archive lives in the same bucket, so its folder isn't one.
+fn is_library_name(name: &str) -> bool {
+    is_valid_emoji_name(name) && name != ARCHIVE_PREFIX
+}
+
+/// The images in an emoji's folder, by file name, with their sizes in bytes.  The first is the one
+/// imported when no variant is picked.
+async fn variants(bucket: &Bucket, emoji: &str) -> anyhow::Result<Vec<(String, u64)>> {
+    let prefix = format!("{emoji}/");
+    let pages = bucket.list(prefix.clone(), Some("/".to_owned())).await?;
+    Ok(pages
+        .iter()
+        .flat_map(|page| page.contents.iter())
+        .filter_map(|object| {
+            let file = object.key.strip_prefix(&prefix)?;
+            (!file.is_empty()).then(|| (file.to_string(), object.size))
+        })
+        .collect())
+}
 
-    let image_data = match bucket.get_object(&file_list[0].contents[0].key).await {
-        Ok(rs) => rs,
+/// Autocomplete choices for the variants of `emoji` whose file names contain `typed`.
+async fn variant_choices(emoji: &str, typed: &str) -> Vec<Value> {
+    if !is_library_name(emoji) {
+        return vec![];
+    }
+    let files = match emoji_bucket() {
+        Ok(bucket) => variants(&bucket, emoji).await,
+        Err(e) => Err(e),
+    };
+    let files = match files {
+        Ok(files) => files,
         Err(e) => {
-            error!("Could not retrieve image from s3 bucket: {e}");
-            return;
+            error!("Unable to list variants of {emoji}: {e:#}");
+            return vec![];
         }
     };
+    matching_variants(files, typed)
+        .into_iter()
+        .map(|(name, file)| json!({"name": name, "value": file}))
+        .collect()
+}
 
-    let image_b64 = base64::encode(image_data.as_slice());
-    let image_str = format!("data:image/png;base64,{}", image_b64);
-    let emoji_name_sanitized = emoji_name.as_str().unwrap().replace('-', "_");
+/// The variants whose file names contain `typed`, as autocomplete choice names and values.
+fn matching_variants(files: Vec<(String, u64)>, typed: &str) -> Vec<(String, String)> {
+    files
+        .into_iter()
+        // discord takes at most 25 choices, with names and values up to 100 characters.
+        .filter(|(file, _)| file.contains(typed) && file.chars().count() <= 100)
+        .take(25)
+        .map(|(file, size)| {
+            let name = format!("{file} ({} KiB)", size.div_ceil(1024));
+            (name.chars().take(100).collect(), file)
+        })
+        .collect()
+}
+
+/// Convert and shrink an image as needed, then add it to the server as an emoji.
+async fn install_emoji(
+    ctx: &Context,
+    guild: GuildId,
+    name: &str,
+    bytes: Vec<u8>,
+) -> anyhow::Result<Emoji> {
+    // shrinking a long GIF can take seconds, too long to hold up one of the runtime's workers.
+    let image = tokio::task::spawn_blocking(move || {
+        EmojiImage::from_bytes(bytes).and_then(EmojiImage::fit)
+    })
+    .await??;
+    info!(format = ?image.format, animated = image.animated, "Uploading emoji.");
+    let name = name.replace('-', "_");
     match guild
-        .create_emoji(&ctx.http, &emoji_name_sanitized, &image_str)
+        .create_emoji(&ctx.http, &name, &image.data_uri())
         .await
     {
-        Ok(_) => {
-            if let Err(e) = command
-                .create_interaction_response(&ctx.http, |resp| {
-                    resp.kind(InteractionResponseType::ChannelMessageWithSource)
-                        .interaction_response_data(|message| {
-                            message.content(format!(
-                                "Emoji :{}: added to server.",
-                                &emoji_name_sanitized
-                            ))
-                        })
-                })
-                .await
-            {
-                error!("Unable to send response to command: {}", e);
-            }
-        }
-        Err(e) => {
-            error!("Could not add emoji: {}", e);
-        }
+        Ok(emoji) => Ok(emoji),
+        Err(e) => match DiscordError::from(e) {
+            e if e.is_misconfiguration() => bail!("{e}, it needs Manage Emojis and Stickers"),
+            e => bail!(e),
+        },
     }
 }
 
+/// What to tell whoever added an emoji.
+fn added(emoji: &Emoji) -> String {
+    match emoji.animated {
+        true => format!("Animated emoji :{}: added to server.", emoji.name),
+        false => format!("Emoji :{}: added to server.", emoji.name),
+    }
+}
+
+/// The bucket the emoji library lives in.
+fn emoji_bucket() -> anyhow::Result<Bucket> {
+    let Ok(s3_endpoint) = env::var("EMOJI_S3_ENDPOINT") else {
+        bail!("need an s3 endpoint for emojis");
+    };
+    let Ok(s3_bucket) = env::var("EMOJI_S3_BUCKET") else {
+        bail!("need a bucket name for emojis");
+    };
+    let bucket = Bucket::new(
+        &s3_bucket,
+        Region::Custom {
+            region: "us-east-1".to_owned(),
+            endpoint: s3_endpoint,
+        },
+        Credentials::default()?,
+    )?
+    .with_path_style();
+    Ok(bucket)
+}
+
 pub async fn do_emoji_autocomplete(ctx: &Context, command: AutocompleteInteraction) {
+    let options = &command.data.options;
+    if let Some(variant) = options
+        .iter()
+        .find(|opt| opt.focused && opt.name == "variant")
+    {
+        let emoji = option_str(options, "emoji").unwrap_or_default();
+        let typed = variant.value.as_ref().and_then(Value::as_str);
+        let choices = variant_choices(emoji, typed.unwrap_or_default()).await;
+        if let Err(e) = command
+            .create_autocomplete_response(&ctx.http, |resp| resp.set_choices(json!(choices)))
+            .await
+        {
+            error!("couldn't send autocomplete response: {e}");
+        }
+        return;
+    }
+
     let emoji_option: Vec<&CommandDataOption> = command
         .data
         .options
@@ -234,29 +345,8 @@ pub struct EmojiAutocompleteOption {
     value: String,
 }
 
-pub async fn do_emoji_indexing(_url: String) -> anyhow::Result<()> {
-    let s3_endpoint = env::var("EMOJI_S3_ENDPOINT").ok();
-    let s3_bucket = env::var("EMOJI_S3_BUCKET").ok();
-    if s3_endpoint.is_none() {
-        bail!("need an s3 endpoint for emojis");
-    }
-    if s3_bucket.is_none() {
-        bail!("need a bucket name for emojis");
-    }
-    let Some(url) = env::var("MEILISEARCH_URL").ok() else {
-        bail!("MEILISEARCH_URL not defined")
-    };
-
-    let bucket = Bucket::new(
-        &s3_bucket.unwrap(),
-        Region::Custom {
-            region: "us-east-1".to_owned(),
-            endpoint: s3_endpoint.unwrap(),
-        },
-        Credentials::default().unwrap(),
-    )
-    .unwrap()
-    .with_path_style();
+pub async fn do_emoji_indexing(url: String) -> anyhow::Result<()> {
+    let bucket = emoji_bucket()?;
 
     let filenames = match get_emoji_directory_names(bucket).await {
         Some(f) => f,
@@ -297,10 +387,13 @@ async fn get_emoji_directory_names(bucket: Bucket) -> Option<Vec<String>> {
     };
     if !list_result.is_empty() {
         let prefixes = list_result[0].clone().common_prefixes;
-        if prefixes.is_some() {
-            prefixes.unwrap().iter().for_each(|dir| {
-                let dirname = dir.prefix.clone();
-                dirnames.push(dirname.strip_suffix('/').unwrap().to_string());
+        if let Some(prefixes) = prefixes {
+            prefixes.iter().for_each(|dir| {
+                let dirname = dir.prefix.strip_suffix('/').unwrap().to_string();
+                // the sweeper archives messages into the same bucket.
+                if dirname != ARCHIVE_PREFIX {
+                    dirnames.push(dirname);
+                }
             });
         }
         Some(dirnames)
@@ -319,3 +412,72 @@ fn is_valid_meili_key(key: &str) -> bool {
     // there were still chars that weren't alphanumeric after we removed hyphens.
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emoji_names_follow_discords_rules() {
+        assert!(is_valid_emoji_name("ok"));
+        assert!(is_valid_emoji_name("party-parrot_2"));
+        assert!(is_valid_emoji_name(&"a".repeat(32)));
+        assert!(!is_valid_emoji_name("a"));
+        assert!(!is_valid_emoji_name(&"a".repeat(33)));
+        assert!(!is_valid_emoji_name("party parrot"));
+        assert!(!is_valid_emoji_name("parrot.gif"));
+        assert!(!is_valid_emoji_name("../parrot"));
+    }
+
+    #[test]
+    fn emoji_names_are_ascii_and_counted_in_characters() {
+        assert!(!is_valid_emoji_name("perroquet_é"));
+        assert!(!is_valid_emoji_name("パロット"));
+        assert!(!is_valid_emoji_name("١٢٣"));
+        // 16 characters, but 32 bytes.
+        assert!(!is_valid_emoji_name(&"é".repeat(16)));
+    }
+
+    #[test]
+    fn the_message_archive_isnt_an_emoji() {
+        assert!(is_library_name("parrot"));
+        assert!(!is_library_name(ARCHIVE_PREFIX));
+        assert!(!is_library_name("a"));
+        // a server emoji can still be called that.
+        assert!(is_valid_emoji_name(ARCHIVE_PREFIX));
+    }
+
+    #[test]
+    fn variants_match_on_file_name() {
+        let files = vec![
+            ("parrot.gif".to_string(), 2048),
+            ("parrot-small.png".to_string(), 100),
+            ("fast.gif".to_string(), 4097),
+        ];
+        assert_eq!(
+            matching_variants(files, "parrot"),
+            [
+                ("parrot.gif (2 KiB)".to_string(), "parrot.gif".to_string()),
+                (
+                    "parrot-small.png (1 KiB)".to_string(),
+                    "parrot-small.png".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn variant_choices_fit_discords_limits() {
+        // a value over 100 characters can't be picked at all.
+        let files: Vec<(String, u64)> = [("x".repeat(101), 1)]
+            .into_iter()
+            .chain((0..30).map(|i| (format!("{i}-{}", "é".repeat(95)), 1024)))
+            .collect();
+        let choices = matching_variants(files, "");
+        assert_eq!(choices.len(), 25);
+        for (name, file) in choices {
+            assert!(file.chars().count() <= 100);
+            assert_eq!(name.chars().count(), 100);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use super::convert::{detect, EmojiImage};
+use super::{
+    added, emoji_bucket, install_emoji, is_library_name, is_valid_emoji_name, variants,
+    EmojiSearch, EMOJI_NAME_RULES,
+};
+use crate::commands::err_response;
+use anyhow::{bail, Context as _, Result};
+use meilisearch_sdk::client::Client as meili;
+use s3::Bucket;
+use serenity::builder::CreateApplicationCommand;
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOptionValue,
+};
+use serenity::model::channel::Attachment;
+use serenity::model::id::GuildId;
+use serenity::model::permissions::Permissions;
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::InteractionResponseType;
+use serenity::prelude::*;
+use std::env;
+
+pub const EMOJI_UPLOAD_COMMAND: &str = "emoji-upload";
+const EMOJI_UPLOAD_DESCRIPTION: &str = "Add an emoji to the emoji library";
+
+/// The biggest file the library takes.  It's shrunk to fit when it's installed, not when it's
+/// stored.
+const MAX_UPLOAD_BYTES: u64 = 10 * 1024 * 1024;
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name(EMOJI_UPLOAD_COMMAND)
+        .description(EMOJI_UPLOAD_DESCRIPTION)
+        .default_member_permissions(Permissions::MANAGE_EMOJIS_AND_STICKERS)
+        .create_option(|option| {
+            option
+                .name("name")
+                .kind(CommandOptionType::String)
+                .required(true)
+                .description("Name of the emoji: letters, numbers, - and _")
+        })
+        .create_option(|option| {
+            option
+                .name("image")
+                .kind(CommandOptionType::Attachment)
+                .required(true)
+                .description("The emoji's image")
+        })
+        .create_option(|option| {
+            option
+                .name("install")
+                .kind(CommandOptionType::Boolean)
+                .description("Also add it to this server")
+        })
+}
+
+pub async fn do_emoji_upload(ctx: &Context, command: ApplicationCommandInteraction) {
+    let Some(guild) = command.guild_id else {
+        error!("No server associated with emoji upload...?");
+        return;
+    };
+    let mut name = None;
+    let mut attachment = None;
+    let mut install = false;
+    for option in command.data.options.iter() {
+        match (option.name.as_str(), &option.resolved) {
+            ("name", Some(CommandDataOptionValue::String(s))) => name = Some(s.clone()),
+            ("image", Some(CommandDataOptionValue::Attachment(a))) => attachment = Some(a.clone()),
+            ("install", Some(CommandDataOptionValue::Boolean(b))) => install = *b,
+            _ => {}
+        }
+    }
+    let (Some(name), Some(attachment)) = (name, attachment) else {
+        err_response(ctx, &command, "need a name and an image").await;
+        return;
+    };
+
+    if !is_valid_emoji_name(&name) {
+        err_response(ctx, &command, EMOJI_NAME_RULES).await;
+        return;
+    }
+    // the sweeper archives messages into the same bucket.
+    if !is_library_name(&name) {
+        err_response(
+            ctx,
+            &command,
+            &format!("{name} is reserved, pick another name"),
+        )
+        .await;
+        return;
+    }
+    if attachment.size > MAX_UPLOAD_BYTES {
+        err_response(
+            ctx,
+            &command,
+            &format!(
+                "that's too big, the library takes images up to {} MiB",
+                MAX_UPLOAD_BYTES / 1024 / 1024
+            ),
+        )
+        .await;
+        return;
+    }
+    let bucket = match emoji_bucket() {
+        Ok(bucket) => bucket,
+        Err(e) => {
+            err_response(ctx, &command, &format!("bot is misconfigured: {e:#}")).await;
+            error!("{e:#}");
+            return;
+        }
+    };
+
+    // downloading, storing and installing can take longer than discord waits for a response.
+    if let Err(e) = command
+        .create_interaction_response(&ctx.http, |resp| {
+            resp.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        })
+        .await
+    {
+        error!("Unable to defer response to command: {}", e);
+        return;
+    }
+    let reply = match upload(ctx, guild, &bucket, &name, &attachment, install).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            error!("Could not upload emoji {name}: {e:#}");
+            format!("**error**: couldn't upload emoji: {e:#}")
+        }
+    };
+    if let Err(e) = command
+        .edit_original_interaction_response(&ctx.http, |resp| resp.content(reply))
+        .await
+    {
+        error!("Unable to send response to command: {}", e);
+    }
+}
+
+/// Store the attachment under `<name>/` in the emoji bucket and make it searchable straight away,
+/// then add it to the server too if asked.  An emoji that's already in the library gets it as
+/// another variant.  Returns what to tell whoever uploaded it.
+async fn upload(
+    ctx: &Context,
+    guild: GuildId,
+    bucket: &Bucket,
+    name: &str,
+    attachment: &Attachment,
+    install: bool,
+) -> Result<String> {
+    let existing = variants(bucket, name)
+        .await
+        .context("couldn't list the emoji library")?;
+
+    let bytes = attachment
+        .download()
+        .await
+        .context("couldn't download the image")?;
+    let format = detect(&bytes)?;
+    // make sure it'll import before it's stored.
+    let image = bytes.clone();
+    tokio::task::spawn_blocking(move || EmojiImage::from_bytes(image)).await??;
+    let file = format!(
+        "{}.{}",
+        file_stem(&attachment.filename).unwrap_or(name),
+        format.extensions_str()[0]
+    );
+    if existing.iter().any(|(existing, _)| *existing == file) {
+        bail!("{name} already has a variant called {file}");
+    }
+    let key = format!("{name}/{file}");
+    bucket
+        .put_object_with_content_type(&key, &bytes, format.to_mime_type())
+        .await
+        .context("couldn't store it in s3")?;
+    info!(key, "Uploaded emoji to the library.");
+
+    let mut reply = match existing.is_empty() {
+        true => format!("Added {name} to the emoji library."),
+        false => format!("Added {file} to {name}'s variants in the emoji library."),
+    };
+    match env::var("MEILISEARCH_URL") {
+        Ok(url) => {
+            let client = meili::new(url, env::var("MEILISEARCH_KEY").ok());
+            let document = [EmojiSearch {
+                name: name.to_string(),
+            }];
+            if let Err(e) = client
+                .index("emoji")
+                .add_documents(&document, Some("name"))
+                .await
+            {
+                error!("Unable to index emoji {name}: {e}");
+                reply.push_str(" It won't show up in search until the next reindex.");
+            }
+        }
+        Err(_) => reply.push_str(" Search isn't set up, so it won't autocomplete."),
+    }
+
+    if install {
+        match install_emoji(ctx, guild, name, bytes).await {
+            Ok(emoji) => reply.push_str(&format!("\n{}", added(&emoji))),
+            Err(e) => {
+                error!("Could not add emoji: {e:#}");
+                reply.push_str(&format!("\nCouldn't add it to the server: {e:#}"));
+            }
+        }
+    }
+    Ok(reply)
+}
+
+/// An uploaded file's name without its extension, if it's safe to use as a variant's name.
+fn file_stem(filename: &str) -> Option<&str> {
+    let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    let safe = stem
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    (safe && !stem.is_empty() && stem.len() <= 64).then_some(stem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::archive::ARCHIVE_PREFIX;
+
+    #[test]
+    fn file_stems_are_safe_variant_names() {
+        assert_eq!(file_stem("parrot.gif"), Some("parrot"));
+        assert_eq!(file_stem("party-parrot_2.png"), Some("party-parrot_2"));
+        assert_eq!(file_stem("parrot"), Some("parrot"));
+        let longest = format!("{}.png", "a".repeat(64));
+        assert_eq!(file_stem(&longest), Some(&longest[..64]));
+    }
+
+    #[test]
+    fn uploads_cant_go_in_the_message_archive() {
+        assert!(is_library_name("parrot"));
+        assert!(!is_library_name(ARCHIVE_PREFIX));
+    }
+
+    #[test]
+    fn unsafe_file_names_have_no_stem() {
+        assert_eq!(file_stem(".gif"), None);
+        assert_eq!(file_stem("parrot.tar.gz"), None);
+        assert_eq!(file_stem("../parrot.png"), None);
+        assert_eq!(file_stem("party parrot.png"), None);
+        assert_eq!(file_stem("perroquet-é.png"), None);
+        assert_eq!(file_stem(&format!("{}.png", "a".repeat(65))), None);
+    }
+}
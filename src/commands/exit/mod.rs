@@ -1,15 +1,13 @@
-use anyhow::{bail, Result};
+use crate::models::shutdown::Shutdown;
 use serenity::framework::standard::CommandResult;
 use serenity::model::prelude::*;
 use serenity::prelude::*;
-use std::time::Duration;
-use tokio::time::sleep;
 
+/// Shut down the same way SIGTERM does, letting sweeps and commands that are running finish first.
 pub async fn do_exit(ctx: &Context, msg: &Message) -> CommandResult {
     info!("Exiting bot...");
     msg.reply(ctx, "Daisy... Daisy... give me your answer... please...")
         .await?;
-    let _ = sleep(Duration::from_secs(1));
-    std::process::exit(0);
+    Shutdown::get().begin("exit command");
     Ok(())
 }
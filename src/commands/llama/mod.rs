@@ -1,28 +1,28 @@
 use anyhow::{bail, Result};
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
 use serde::Deserialize;
-use serde_json::{from_str, json, Value};
-use serenity::framework::standard::{CommandError, CommandResult};
+use serde_json::json;
+use serenity::framework::standard::CommandResult;
 use serenity::model::prelude::*;
 use serenity::prelude::*;
-use std::collections::HashMap;
 use std::str;
 
 const LLAMA_URL: &str = "http://dell-r6415.internal:11434";
-const DISCORD_MSG_SIZE_LIMIT: usize = 2000;
+pub(crate) const DISCORD_MSG_SIZE_LIMIT: usize = 2000;
 const SYSTEM_PROMPT: &str = r#"
 You are a bot running in a discord server full of middle-aged technologists.  They appreciate concise answers when possible.  Don't over-embellish or fluff answers.  Being snarky or witty is definitely appreciated.
 
 Markdown is supported, but prefer using simple paragraph-based text whenever possible.  Try not to use emojis unless it makes sense to do so.
 "#;
+const DIGEST_PROMPT: &str = r#"
+You summarize a stretch of conversation from a discord channel before it's deleted, so people who missed it can catch up.  Each line of the transcript is one message: the time, who sent it, then what they said.
+
+Write a few short paragraphs, under 200 words in all, covering the topics discussed, anything decided or planned, and any links or recommendations worth keeping.  Refer to people by name.  Don't quote the transcript at length, don't add a title, and don't comment on the conversation itself.
+"#;
 
 #[derive(Deserialize)]
 struct ParsedChunk {
-    model: String,
-    created_at: String,
     response: Option<String>,
-    done: bool,
 }
 
 pub struct OllamaApi {
@@ -38,7 +38,7 @@ impl OllamaApi {
         OllamaApi { client: rclient }
     }
     pub async fn get_models(&self) -> Result<String> {
-        let mut rs = match self
+        let rs = match self
             .client
             .get(format!("{LLAMA_URL}/api/tags"))
             .send()
@@ -50,14 +50,21 @@ impl OllamaApi {
         Ok(String::from_utf8(Vec::from(rs.bytes().await.unwrap())).unwrap())
     }
     pub async fn doit(&self, prompt: String) -> Result<String> {
+        info!("Prompt: {prompt}");
+        self.generate(SYSTEM_PROMPT, prompt).await
+    }
+    /// Summarize a transcript of a channel's messages, one message per line.
+    pub async fn summarize(&self, transcript: String) -> Result<String> {
+        self.generate(DIGEST_PROMPT, transcript).await
+    }
+    async fn generate(&self, system: &str, prompt: String) -> Result<String> {
         let data = json!({
             "model": "qwen3-4b-pm",
-            "system": SYSTEM_PROMPT,
+            "system": system,
             "prompt": prompt,
             "stream": false
         });
-        info!("Prompt: {prompt}");
-        let mut response = match self
+        let response = match self
             .client
             .post(format!("{LLAMA_URL}/api/generate"))
             .json(&data)
@@ -79,7 +86,7 @@ impl OllamaApi {
                 if let Some(word) = pc.response {
                     retval.push(word);
                 } else {
-                    let msg = format!("Empty response from API.");
+                    let msg = "Empty response from API.".to_string();
                     warn!(msg);
                     bail!(msg);
                 }
@@ -111,7 +118,7 @@ pub async fn do_llama(ctx: &Context, msg: &Message) -> CommandResult {
     msg.reply(ctx, "Give me a moment and I'll fetch you an answer.")
         .await?;
     let ollama = OllamaApi::new();
-    let response = match ollama.doit(query.clone()).await {
+    match ollama.doit(query.clone()).await {
         Ok(s) => {
             if s.len() > DISCORD_MSG_SIZE_LIMIT {
                 let whole_payload = s
@@ -153,7 +160,7 @@ pub async fn do_llama(ctx: &Context, msg: &Message) -> CommandResult {
             }
         }
         Err(e) => {
-            error!(query = query, "failed to execute ollama query.");
+            error!(query = query, "failed to execute ollama query: {e}");
             if let Err(ee) = msg
                 .reply(
                     ctx,
@@ -166,20 +173,20 @@ pub async fn do_llama(ctx: &Context, msg: &Message) -> CommandResult {
         }
     };
 
-    if typing.is_some() {
-        typing.unwrap().stop();
+    if let Some(typing) = typing {
+        typing.stop();
     }
     Ok(())
 }
 
 pub async fn do_llama_models(ctx: &Context, msg: &Message) -> CommandResult {
-    let channel = msg.channel_id;
     let ollama = OllamaApi::new();
-    let response = match ollama.get_models().await {
+    match ollama.get_models().await {
         Ok(s) => {
             msg.reply(ctx, s.replace(r#"\n"#, "\n")).await?;
         }
         Err(e) => {
+            error!("failed to fetch ollama models: {e}");
             msg.reply(
                 ctx,
                 "Sorry, I wasn't able to answer your question right now.",
@@ -4,8 +4,10 @@ use serenity::prelude::*;
 
 pub mod emoji;
 pub mod llama;
+pub mod purge;
 pub mod stats;
 pub mod stonks;
+pub mod sweeper;
 
 pub mod exit;
 
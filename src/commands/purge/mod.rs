@@ -0,0 +1,239 @@
+use crate::commands::err_response;
+use crate::models::manager::SweeperManagerKey;
+use crate::models::sweeper::PurgeReport;
+use serenity::builder::CreateApplicationCommand;
+use serenity::model::application::component::ButtonStyle;
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOptionValue,
+};
+use serenity::model::application::interaction::message_component::MessageComponentInteraction;
+use serenity::model::id::UserId;
+use serenity::model::permissions::Permissions;
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::InteractionResponseType;
+use serenity::prelude::*;
+
+pub const FORGET_ME_COMMAND: &str = "forget-me";
+const FORGET_ME_DESCRIPTION: &str = "Delete every message you've sent in the swept channels";
+
+pub const PURGE_USER_COMMAND: &str = "purge-user";
+const PURGE_USER_DESCRIPTION: &str = "Delete every message a member has sent in the swept channels";
+
+/// Custom ids of the confirmation buttons.  Confirming carries the member being purged, as
+/// `purge:<user id>`.
+pub const PURGE_BUTTON_PREFIX: &str = "purge";
+const PURGE_CANCEL: &str = "purge-cancel";
+
+pub fn register_forget_me(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name(FORGET_ME_COMMAND)
+        .description(FORGET_ME_DESCRIPTION)
+}
+
+/// Only members who can manage messages see `/purge-user`.
+pub fn register_purge_user(
+    command: &mut CreateApplicationCommand,
+) -> &mut CreateApplicationCommand {
+    command
+        .name(PURGE_USER_COMMAND)
+        .description(PURGE_USER_DESCRIPTION)
+        .default_member_permissions(Permissions::MANAGE_MESSAGES)
+        .create_option(|option| {
+            option
+                .name("user")
+                .kind(CommandOptionType::User)
+                .required(true)
+                .description("Whose messages to delete")
+        })
+}
+
+pub async fn do_forget_me(ctx: &Context, command: ApplicationCommandInteraction) {
+    let prompt = "This deletes every message you've sent in the channels the bot sweeps, \
+                  threads included.  It can't be undone.";
+    confirm(ctx, &command, command.user.id, prompt).await;
+}
+
+pub async fn do_purge_user(ctx: &Context, command: ApplicationCommandInteraction) {
+    let user = command
+        .data
+        .options
+        .iter()
+        .find_map(|opt| match &opt.resolved {
+            Some(CommandDataOptionValue::User(user, _)) => Some(user.id),
+            _ => None,
+        });
+    let Some(user) = user else {
+        err_response(ctx, &command, "which user?").await;
+        return;
+    };
+    let prompt = format!(
+        "This deletes every message <@{user}> has sent in the channels the bot sweeps, threads \
+         included.  It can't be undone."
+    );
+    confirm(ctx, &command, user, &prompt).await;
+}
+
+/// Ask for confirmation before purging anything.  The prompt is ephemeral, so only whoever ran the
+/// command can press its buttons.
+async fn confirm(
+    ctx: &Context,
+    command: &ApplicationCommandInteraction,
+    user: UserId,
+    prompt: &str,
+) {
+    if let Err(e) = command
+        .create_interaction_response(&ctx.http, |resp| {
+            resp.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| {
+                    message.content(prompt).ephemeral(true).components(|c| {
+                        c.create_action_row(|row| {
+                            row.create_button(|b| {
+                                b.custom_id(format!("{PURGE_BUTTON_PREFIX}:{user}"))
+                                    .label("Delete them")
+                                    .style(ButtonStyle::Danger)
+                            })
+                            .create_button(|b| {
+                                b.custom_id(PURGE_CANCEL)
+                                    .label("Cancel")
+                                    .style(ButtonStyle::Secondary)
+                            })
+                        })
+                    })
+                })
+        })
+        .await
+    {
+        error!("Unable to send purge confirmation: {}", e);
+    }
+}
+
+/// Handle a press of one of the confirmation buttons: purge the member's messages channel by
+/// channel, keeping the prompt updated as it goes, and finish with a report.
+pub async fn do_purge_button(ctx: &Context, component: MessageComponentInteraction) {
+    let user = component
+        .data
+        .custom_id
+        .strip_prefix(PURGE_BUTTON_PREFIX)
+        .and_then(|id| id.strip_prefix(':'))
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(UserId);
+    let Some(user) = user else {
+        update(
+            ctx,
+            &component,
+            "Cancelled, nothing was deleted.".to_string(),
+        )
+        .await;
+        return;
+    };
+
+    // someone else's messages take manage messages, same as `/purge-user` itself.
+    let moderator = component
+        .member
+        .as_ref()
+        .and_then(|m| m.permissions)
+        .is_some_and(|p| p.manage_messages());
+    if user != component.user.id && !moderator {
+        update(
+            ctx,
+            &component,
+            "You can't purge someone else's messages.".to_string(),
+        )
+        .await;
+        return;
+    }
+    let reason = match user == component.user.id {
+        true => FORGET_ME_COMMAND.to_string(),
+        false => format!("{PURGE_USER_COMMAND} by {}", component.user.name),
+    };
+
+    let manager = match ctx.data.read().await.get::<SweeperManagerKey>() {
+        Some(m) => m.clone(),
+        None => {
+            update(
+                ctx,
+                &component,
+                "Sweepers aren't running yet, try again shortly.".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    let sweepers = manager.lock().await.purge_sweepers();
+    info!(%user, %reason, channels = sweepers.len(), "Purging a member's messages.");
+    update(
+        ctx,
+        &component,
+        format!("Purging <@{user}>'s messages, this can take a while."),
+    )
+    .await;
+    let mut report = PurgeReport::default();
+    let total = sweepers.len();
+    for (done, mut sweeper) in sweepers.into_iter().enumerate() {
+        sweeper.purge_author(user, &reason, &mut report).await;
+        progress(
+            ctx,
+            &component,
+            format!(
+                "Purging <@{user}>'s messages: {} of {total} channels done, {} messages deleted so far.",
+                done + 1,
+                report.run.deleted
+            ),
+        )
+        .await;
+    }
+
+    info!(%user, ?report, "Purged a member's messages.");
+    let mut summary = format!(
+        "Done: deleted {} of <@{user}>'s messages from {} channels and threads.",
+        report.run.deleted, report.channels
+    );
+    if report.found > report.run.deleted {
+        summary.push_str(&format!(
+            " {} couldn't be deleted{}, running it again may get them.",
+            report.found - report.run.deleted,
+            match report.run.errors {
+                0 => String::new(),
+                errors => format!(" ({errors} errors, see the logs)"),
+            }
+        ));
+    }
+    // the interaction expires after fifteen minutes, which a big purge can outlast.
+    if !progress(ctx, &component, summary.clone()).await {
+        if let Err(e) = component
+            .user
+            .direct_message(&ctx.http, |m| m.content(summary))
+            .await
+        {
+            error!("Couldn't send the purge report: {e}");
+        }
+    }
+}
+
+/// Replace the prompt and its buttons with `content`.
+async fn update(ctx: &Context, component: &MessageComponentInteraction, content: String) {
+    if let Err(e) = component
+        .create_interaction_response(&ctx.http, |resp| {
+            resp.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|message| message.content(content).components(|c| c))
+        })
+        .await
+    {
+        error!("Unable to update purge prompt: {}", e);
+    }
+}
+
+/// Edit the prompt after it's been updated.  Returns whether it worked.
+async fn progress(ctx: &Context, component: &MessageComponentInteraction, content: String) -> bool {
+    match component
+        .edit_original_interaction_response(&ctx.http, |resp| resp.content(content))
+        .await
+    {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Unable to update purge progress: {}", e);
+            false
+        }
+    }
+}
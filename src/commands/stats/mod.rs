@@ -1,55 +1,89 @@
+use crate::commands::err_response;
+use crate::models::db::{DatabaseKey, RunRecord, Totals};
 use crate::models::sweeper::{Stats, StatsReceiver};
 use chrono::Utc;
 use human_duration::human_duration;
-use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
+};
+use serenity::model::id::ChannelId;
 use serenity::model::prelude::interaction::InteractionResponseType;
 use serenity::prelude::*;
 
+const RECENT_RUNS: u32 = 5;
+
+/// Discord takes at most 10 embeds in a message, with at most 6000 characters between them.
+const MAX_EMBEDS: usize = 10;
+const MAX_EMBED_CHARS: usize = 6000;
+
 pub async fn do_stats(ctx: &Context, command: ApplicationCommandInteraction) {
-    let vec_stats = match get_stats(ctx).await {
+    let mut vec_stats = match get_stats(ctx).await {
         None => {
             error!("Stats don't exist, but they should.");
             return;
         }
         Some(stats) => stats,
     };
+    // one channel gets its recent runs too; all of them only get their totals, to fit.
+    let channel = channel_option_value(&command.data.options);
+    if let Some(channel_id) = channel {
+        vec_stats.retain(|s| s.channel_id == channel_id);
+        if vec_stats.is_empty() {
+            err_response(ctx, &command, &format!("{channel_id} isn't swept")).await;
+            return;
+        }
+    }
+    let history = get_history(ctx, &vec_stats, channel.is_some()).await;
+
+    // sweepers can be restarted through /sweeper, so the oldest one is the best guess at how
+    // long the bot has been up.
+    let started = vec_stats
+        .iter()
+        .map(|s| s.started)
+        .min()
+        .unwrap_or_else(Utc::now);
+    let uptime = (Utc::now() - started)
+        .to_std()
+        .expect("Duration should be in range");
+    let mut overview = vec![
+        ("Version", env!("CARGO_PKG_VERSION").to_string()),
+        ("GitHash", env!("GIT_HASH").to_string()),
+        ("Uptime", human_duration(&uptime)),
+    ];
+
+    // leave room in the budget for saying how many channels didn't fit.
+    let mut used = fields_len(&overview) + 100;
+    let mut channels = vec![];
+    for (stats, (totals, recent)) in vec_stats.iter().zip(history) {
+        let fields = channel_fields(stats, &totals, channel.map(|_| recent.as_slice()));
+        used += fields_len(&fields);
+        if channels.len() + 1 >= MAX_EMBEDS || used > MAX_EMBED_CHARS {
+            break;
+        }
+        channels.push(fields);
+    }
+    if channels.len() < vec_stats.len() {
+        overview.push((
+            "More Channels",
+            format!(
+                "+{} more, use the `channel` option to see one.",
+                vec_stats.len() - channels.len()
+            ),
+        ));
+    }
 
     if let Err(error) = command
         .create_interaction_response(&ctx.http, |resp| {
             resp.kind(InteractionResponseType::ChannelMessageWithSource)
                 .interaction_response_data(|mut message| {
-                    let uptime = (Utc::now() - vec_stats[0].started)
-                        .to_std()
-                        .expect("Duration should be in range");
-
-                    message
+                    message = message
                         .content(":wave: Hey there, here are some sweeper stats")
-                        .embed(|embed| {
-                            embed
-                                .field("Version", env!("CARGO_PKG_VERSION"), false)
-                                .field("GitHash", env!("GIT_HASH"), false)
-                                .field("Uptime", human_duration(&uptime), false)
-                        });
-
-                    for stats in vec_stats {
-                        message = message.embed(|embed| {
-                            embed
-                                .field("Channel", stats.channel_id, false)
-                                .field("Runs", format!("Ran {} times", stats.runs), false)
-                                .field(
-                                    "Last Run",
-                                    format!("Cleaned up {} messages.", stats.last_run),
-                                    false,
-                                )
-                                .field(
-                                    "All Runs",
-                                    format!("Cleaned up {} messages.", stats.all_runs),
-                                    false,
-                                )
-                        })
+                        .embed(|embed| embed.fields(overview.iter().map(|(n, v)| (n, v, false))));
+                    for fields in &channels {
+                        message = message
+                            .embed(|embed| embed.fields(fields.iter().map(|(n, v)| (n, v, false))));
                     }
-                    message = message.ephemeral(true);
-                    message
+                    message.ephemeral(true)
                 })
         })
         .await
@@ -57,6 +91,132 @@ pub async fn do_stats(ctx: &Context, command: ApplicationCommandInteraction) {
         error!(error = %error, "Failed to respond to status command.");
     }
 }
+
+/// What `/stats` shows for one channel.  Recent runs are only shown when asked for.
+fn channel_fields(
+    stats: &Stats,
+    totals: &Totals,
+    recent: Option<&[RunRecord]>,
+) -> Vec<(&'static str, String)> {
+    let since = match totals.first_run {
+        Some(first) => format!(" since {}", first.format("%Y-%m-%d")),
+        None => String::new(),
+    };
+    let mut fields = vec![
+        ("Channel", stats.channel_id.to_string()),
+        (
+            "Runs",
+            format!(
+                "Ran {} times ({} since restart){}",
+                totals.runs, stats.runs, since
+            ),
+        ),
+        (
+            "Last Run",
+            format!("Cleaned up {} messages.", stats.last_run),
+        ),
+        (
+            "All Runs",
+            format!(
+                "Scanned {} messages, cleaned up {}, skipped {} pinned, {} errors.",
+                totals.run.scanned, totals.run.deleted, totals.run.pinned, totals.run.errors
+            ),
+        ),
+    ];
+    if let Some(recent) = recent {
+        fields.push(("Recent Runs", format_recent(recent)));
+    }
+    fields.push((
+        "Threads",
+        format!(
+            "Swept {} threads last run, deleted {} empty threads.",
+            stats.threads_swept, stats.threads_deleted
+        ),
+    ));
+    fields.push((
+        "Old Messages",
+        format!(
+            "Deleted {} one at a time, {} pending.",
+            stats.old_deleted, stats.old_pending
+        ),
+    ));
+    fields
+}
+
+/// How many characters of an embed's limit its fields use.
+fn fields_len(fields: &[(&str, String)]) -> usize {
+    fields
+        .iter()
+        .map(|(name, value)| name.chars().count() + value.chars().count())
+        .sum()
+}
+
+fn channel_option_value(options: &[CommandDataOption]) -> Option<ChannelId> {
+    options
+        .iter()
+        .find(|opt| opt.name == "channel")
+        .and_then(|opt| match &opt.resolved {
+            Some(CommandDataOptionValue::Channel(channel)) => Some(channel.id),
+            _ => None,
+        })
+}
+
+/// Lifetime totals, and the last few runs if wanted, for each channel, from the database.
+async fn get_history(
+    ctx: &Context,
+    vec_stats: &[Stats],
+    with_recent: bool,
+) -> Vec<(Totals, Vec<RunRecord>)> {
+    let db = ctx.data.read().await.get::<DatabaseKey>().cloned();
+    vec_stats
+        .iter()
+        .map(|stats| {
+            let Some(db) = &db else {
+                return (Totals::default(), vec![]);
+            };
+            let totals = db.totals(stats.channel_id).unwrap_or_else(|e| {
+                error!("Couldn't read sweep totals: {e:#}");
+                Totals::default()
+            });
+            if !with_recent {
+                return (totals, vec![]);
+            }
+            let recent = db
+                .recent_runs(stats.channel_id, RECENT_RUNS)
+                .unwrap_or_else(|e| {
+                    error!("Couldn't read recent sweep runs: {e:#}");
+                    vec![]
+                });
+            (totals, recent)
+        })
+        .collect()
+}
+
+fn format_recent(recent: &[RunRecord]) -> String {
+    if recent.is_empty() {
+        return "No runs recorded yet.".to_string();
+    }
+    recent
+        .iter()
+        .map(|r| {
+            let took = match r.finished_at {
+                Some(finished) => format!("{}s", (finished - r.started_at).num_seconds().max(0)),
+                None => "unfinished".to_string(),
+            };
+            format!(
+                "{} ({}): deleted {} of {} scanned, {} pinned, {} errors",
+                r.started_at.format("%Y-%m-%d %H:%M"),
+                took,
+                r.run.deleted,
+                r.run.scanned,
+                r.run.pinned,
+                r.run.errors
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
 async fn get_stats(ctx: &Context) -> Option<Vec<Stats>> {
     let stats = ctx
         .data
@@ -1,12 +1,9 @@
-use anyhow::{bail, Result};
 use serenity::framework::standard::CommandResult;
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 use yahoo_finance_api as yahoo;
 
 pub async fn do_stonks(ctx: &Context, msg: &Message) -> CommandResult {
-    let channel = msg.channel_id;
-
     let query = msg
         .content
         .clone()
@@ -16,8 +13,8 @@ pub async fn do_stonks(ctx: &Context, msg: &Message) -> CommandResult {
     let ticker: String;
     match query.split_once(char::is_whitespace) {
         Some((t, o)) => {
-            if o.len() > 0 {
-                msg.reply(ctx, format!("Please specify one ticker at a time."))
+            if !o.is_empty() {
+                msg.reply(ctx, "Please specify one ticker at a time.")
                     .await?;
                 return Ok(());
             }
@@ -0,0 +1,445 @@
+use crate::commands::err_response;
+use crate::models::config::ChannelConfig;
+use crate::models::db::{AuditFilter, DatabaseKey};
+use crate::models::manager::SweeperManagerKey;
+use crate::models::sweeper::{Stats, StatsReceiver, Sweeper};
+use anyhow::Context as _;
+use chrono::NaiveDate;
+use duration_string::DurationString;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption};
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
+};
+use serenity::model::channel::ChannelType;
+use serenity::model::id::{ChannelId, UserId};
+use serenity::model::permissions::Permissions;
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::InteractionResponseType;
+use serenity::prelude::*;
+use tokio::sync::watch;
+
+pub const SWEEPER_COMMAND: &str = "sweeper";
+const SWEEPER_DESCRIPTION: &str = "Manage the channels the bot sweeps";
+
+/// The most deletions `/sweeper audit` lists, newest first.
+const AUDIT_LIMIT: u32 = 10;
+
+/// Build the `/sweeper` command group.  Only members who can manage messages see it.
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name(SWEEPER_COMMAND)
+        .description(SWEEPER_DESCRIPTION)
+        .default_member_permissions(Permissions::MANAGE_MESSAGES)
+        .create_option(|sub| {
+            subcommand(sub, "add", "Start sweeping a channel");
+            channel_option(sub);
+            sub.create_sub_option(|option| {
+                option
+                    .name("max-age")
+                    .kind(CommandOptionType::String)
+                    .description(
+                        "Age of a message before it's deleted, e.g. 20h or 1d (default 1d)",
+                    )
+            })
+            .create_sub_option(|option| {
+                option
+                    .name("dry-run")
+                    .kind(CommandOptionType::Boolean)
+                    .description("Don't actually delete anything")
+            })
+        })
+        .create_option(|sub| {
+            subcommand(
+                sub,
+                "remove",
+                "Stop sweeping a channel and forget its settings",
+            );
+            channel_option(sub)
+        })
+        .create_option(|sub| {
+            subcommand(
+                sub,
+                "pause",
+                "Stop sweeping a channel but keep its settings",
+            );
+            channel_option(sub)
+        })
+        .create_option(|sub| {
+            subcommand(sub, "resume", "Resume sweeping a paused channel");
+            channel_option(sub)
+        })
+        .create_option(|sub| {
+            subcommand(
+                sub,
+                "set-age",
+                "Change how old a message gets before it's deleted",
+            );
+            channel_option(sub);
+            sub.create_sub_option(|option| {
+                option
+                    .name("max-age")
+                    .kind(CommandOptionType::String)
+                    .required(true)
+                    .description("Age of a message before it's deleted, e.g. 20h or 1d")
+            })
+        })
+        .create_option(|sub| {
+            subcommand(sub, "run-now", "Sweep a channel right away");
+            channel_option(sub)
+        })
+        .create_option(|sub| {
+            subcommand(
+                sub,
+                "preview",
+                "Show what a sweep would delete, without deleting anything",
+            );
+            channel_option(sub);
+            sub.create_sub_option(|option| {
+                option
+                    .name("max-age")
+                    .kind(CommandOptionType::String)
+                    .description("Try a different max age, e.g. 20h or 1d")
+            })
+        })
+        .create_option(|sub| {
+            subcommand(sub, "audit", "Look up messages the sweeper deleted")
+                .create_sub_option(|option| {
+                    option
+                        .name("user")
+                        .kind(CommandOptionType::User)
+                        .description("Who sent the messages")
+                })
+                .create_sub_option(|option| {
+                    option
+                        .name("channel")
+                        .kind(CommandOptionType::Channel)
+                        .description("Where the messages were sent")
+                })
+                .create_sub_option(|option| {
+                    option
+                        .name("from")
+                        .kind(CommandOptionType::String)
+                        .description("Sent on or after this day, YYYY-MM-DD")
+                })
+                .create_sub_option(|option| {
+                    option
+                        .name("to")
+                        .kind(CommandOptionType::String)
+                        .description("Sent on or before this day, YYYY-MM-DD")
+                })
+        })
+        .create_option(|sub| subcommand(sub, "list", "List the swept channels"))
+}
+
+fn subcommand<'a>(
+    sub: &'a mut CreateApplicationCommandOption,
+    name: &str,
+    description: &str,
+) -> &'a mut CreateApplicationCommandOption {
+    sub.name(name)
+        .description(description)
+        .kind(CommandOptionType::SubCommand)
+}
+
+fn channel_option(sub: &mut CreateApplicationCommandOption) -> &mut CreateApplicationCommandOption {
+    sub.create_sub_option(|option| {
+        option
+            .name("channel")
+            .kind(CommandOptionType::Channel)
+            .channel_types(&[ChannelType::Text, ChannelType::News, ChannelType::Forum])
+            .required(true)
+            .description("The swept channel")
+    })
+}
+
+pub async fn do_sweeper(ctx: &Context, command: ApplicationCommandInteraction) {
+    let Some(sub) = command.data.options.first() else {
+        error!("sweeper command invoked without a subcommand");
+        return;
+    };
+
+    let manager = match ctx.data.read().await.get::<SweeperManagerKey>() {
+        Some(m) => m.clone(),
+        None => {
+            err_response(
+                ctx,
+                &command,
+                "sweepers aren't running yet, try again shortly",
+            )
+            .await;
+            return;
+        }
+    };
+
+    if sub.name == "list" {
+        let manager = manager.lock().await;
+        let mut lines: Vec<String> = manager
+            .channels()
+            .iter()
+            .map(|c| {
+                let state = match (c.paused, manager.is_running(c.channel_id())) {
+                    (true, _) => "paused",
+                    (false, true) => "running",
+                    (false, false) => "stopped",
+                };
+                let dry_run = if c.dry_run { ", dry run" } else { "" };
+                let schedule = match c.ttl {
+                    Some(ttl) => format!("ttl {ttl}"),
+                    None => c.schedule.describe(),
+                };
+                let next = match manager.next_run(c.channel_id()) {
+                    Some(next) => format!(", next run <t:{}:R>", next.timestamp()),
+                    None => String::new(),
+                };
+                format!(
+                    "<#{}>: max age {}, {}, {}{}{}",
+                    c.id, c.max_message_age, schedule, state, dry_run, next
+                )
+            })
+            .collect();
+        if lines.is_empty() {
+            lines.push("No channels are swept.".to_string());
+        }
+        drop(manager);
+        respond(ctx, &command, lines.join("\n")).await;
+        return;
+    }
+
+    if sub.name == "audit" {
+        match audit(ctx, sub).await {
+            Ok(message) => respond(ctx, &command, message).await,
+            Err(e) => err_response(ctx, &command, format!("{e:#}").as_str()).await,
+        }
+        return;
+    }
+
+    let Some(channel_id) = channel_option_value(sub) else {
+        err_response(ctx, &command, "a channel is required").await;
+        return;
+    };
+    let max_age = match string_option_value(sub, "max-age")
+        .map(|s| s.parse::<DurationString>())
+        .transpose()
+    {
+        Ok(age) => age,
+        Err(e) => {
+            err_response(ctx, &command, format!("bad max-age: {e}").as_str()).await;
+            return;
+        }
+    };
+
+    if sub.name == "preview" {
+        let mut sweeper = manager.lock().await.preview_sweeper(channel_id, max_age);
+        preview(ctx, &command, &mut sweeper).await;
+        return;
+    }
+
+    let mut manager = manager.lock().await;
+    let result = match sub.name.as_str() {
+        "add" => {
+            let dry_run = bool_option_value(sub, "dry-run").unwrap_or(false);
+            let max_age = max_age.unwrap_or_else(|| "1d".parse().expect("1d is a valid duration"));
+            match manager.add(ChannelConfig::new(channel_id, max_age, dry_run)) {
+                Ok(rx) => {
+                    replace_stats(ctx, channel_id, Some(rx)).await;
+                    Ok(format!(
+                        "Now sweeping <#{}>, messages older than {} are deleted.",
+                        channel_id, max_age
+                    ))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        "remove" => match manager.remove(channel_id) {
+            Ok(()) => {
+                replace_stats(ctx, channel_id, None).await;
+                Ok(format!("No longer sweeping <#{}>.", channel_id))
+            }
+            Err(e) => Err(e),
+        },
+        "pause" => manager
+            .pause(channel_id)
+            .map(|_| format!("Paused sweeping <#{}>.", channel_id)),
+        "resume" => match manager.resume(channel_id) {
+            Ok(rx) => {
+                replace_stats(ctx, channel_id, Some(rx)).await;
+                Ok(format!("Resumed sweeping <#{}>.", channel_id))
+            }
+            Err(e) => Err(e),
+        },
+        "set-age" => {
+            let Some(max_age) = max_age else {
+                drop(manager);
+                err_response(ctx, &command, "max-age is required").await;
+                return;
+            };
+            match manager.set_age(channel_id, max_age) {
+                Ok(rx) => {
+                    if rx.is_some() {
+                        replace_stats(ctx, channel_id, rx).await;
+                    }
+                    Ok(format!(
+                        "Messages in <#{}> are now deleted after {}.",
+                        channel_id, max_age
+                    ))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        "run-now" => manager
+            .run_now(channel_id)
+            .map(|_| format!("Sweeping <#{}> now.", channel_id)),
+        other => {
+            error!("unknown sweeper subcommand {other}");
+            return;
+        }
+    };
+    drop(manager);
+
+    match result {
+        Ok(message) => {
+            info!(subcommand = sub.name, "{message}");
+            respond(ctx, &command, message).await;
+        }
+        Err(e) => {
+            error!(subcommand = sub.name, "sweeper command failed: {e:#}");
+            err_response(ctx, &command, format!("{e:#}").as_str()).await;
+        }
+    }
+}
+
+/// Look up deletions in the audit log.
+async fn audit(ctx: &Context, sub: &CommandDataOption) -> anyhow::Result<String> {
+    let day = |name: &str| -> anyhow::Result<Option<NaiveDate>> {
+        string_option_value(sub, name)
+            .map(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d"))
+            .transpose()
+            .with_context(|| format!("bad {name} date, expected YYYY-MM-DD"))
+    };
+    let filter = AuditFilter {
+        user: user_option_value(sub),
+        channel: channel_option_value(sub),
+        ..AuditFilter::default()
+    }
+    .days(day("from")?, day("to")?);
+
+    let Some(db) = ctx.data.read().await.get::<DatabaseKey>().cloned() else {
+        anyhow::bail!("the audit log isn't available right now, try again shortly");
+    };
+    let records = db.deletions(&filter, AUDIT_LIMIT + 1)?;
+    if records.is_empty() {
+        return Ok("No matching deletions.".to_string());
+    }
+
+    let mut lines: Vec<String> = records
+        .iter()
+        .take(AUDIT_LIMIT as usize)
+        .map(|r| {
+            let d = &r.deletion;
+            let author = match d.author_id {
+                Some(author) => format!("<@{author}>"),
+                None => "unknown".to_string(),
+            };
+            let run = match r.run_id {
+                Some(run) => format!(", run {run}"),
+                None => String::new(),
+            };
+            format!(
+                "`{}` by {} in <#{}>, sent <t:{}:f>, deleted <t:{}:R>: {}{}",
+                d.message_id,
+                author,
+                d.channel_id,
+                d.sent_at.timestamp(),
+                r.deleted_at.timestamp(),
+                d.reason,
+                run
+            )
+        })
+        .collect();
+    if records.len() > AUDIT_LIMIT as usize {
+        lines.push(format!(
+            "Showing the newest {AUDIT_LIMIT}, narrow the search for more."
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Reading through a channel can take longer than discord waits for a response, so defer it and
+/// fill the report in when it's ready.
+async fn preview(ctx: &Context, command: &ApplicationCommandInteraction, sweeper: &mut Sweeper) {
+    if let Err(e) = command
+        .create_interaction_response(&ctx.http, |resp| {
+            resp.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|message| message.ephemeral(true))
+        })
+        .await
+    {
+        error!("Unable to defer response to command: {}", e);
+        return;
+    }
+
+    let report = sweeper.preview().await;
+    if let Err(e) = command
+        .edit_original_interaction_response(&ctx.http, |resp| resp.content(report.render()))
+        .await
+    {
+        error!("Unable to send preview: {}", e);
+    }
+}
+
+/// Swap the stats receiver registered for a channel, or just drop it when `rx` is `None`.
+async fn replace_stats(ctx: &Context, channel_id: ChannelId, rx: Option<watch::Receiver<Stats>>) {
+    let mut data = ctx.data.write().await;
+    if let Some(stats) = data.get_mut::<StatsReceiver>() {
+        stats.retain(|s| s.borrow().channel_id != channel_id);
+        stats.extend(rx);
+    }
+}
+
+async fn respond(ctx: &Context, command: &ApplicationCommandInteraction, content: String) {
+    if let Err(e) = command
+        .create_interaction_response(&ctx.http, |resp| {
+            resp.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| message.content(content).ephemeral(true))
+        })
+        .await
+    {
+        error!("Unable to send response to command: {}", e);
+    }
+}
+
+fn channel_option_value(sub: &CommandDataOption) -> Option<ChannelId> {
+    sub.options
+        .iter()
+        .find(|opt| opt.name == "channel")
+        .and_then(|opt| match &opt.resolved {
+            Some(CommandDataOptionValue::Channel(channel)) => Some(channel.id),
+            _ => None,
+        })
+}
+
+fn user_option_value(sub: &CommandDataOption) -> Option<UserId> {
+    sub.options
+        .iter()
+        .find(|opt| opt.name == "user")
+        .and_then(|opt| match &opt.resolved {
+            Some(CommandDataOptionValue::User(user, _)) => Some(user.id),
+            _ => None,
+        })
+}
+
+fn string_option_value<'a>(sub: &'a CommandDataOption, name: &str) -> Option<&'a str> {
+    sub.options
+        .iter()
+        .find(|opt| opt.name == name)
+        .and_then(|opt| opt.value.as_ref())
+        .and_then(|value| value.as_str())
+}
+
+fn bool_option_value(sub: &CommandDataOption, name: &str) -> Option<bool> {
+    sub.options
+        .iter()
+        .find(|opt| opt.name == name)
+        .and_then(|opt| opt.value.as_ref())
+        .and_then(|value| value.as_bool())
+}
@@ -1,12 +1,22 @@
-use std::borrow::BorrowMut;
 #[macro_use]
 extern crate tracing;
 
 use crate::commands::emoji::do_emoji_indexing;
-use crate::models::sweeper::{run_sweeper, Stats, StatsReceiver, Sweeper};
-use chrono::Duration;
-use clap::Parser;
-use duration_string::DurationString;
+use crate::models::budget::{
+    RequestBudget, DEFAULT_CONCURRENT_REQUESTS, DEFAULT_REQUESTS_PER_SECOND,
+};
+use crate::models::config::Config;
+use crate::models::db::{Database, DatabaseKey};
+use crate::models::manager::{Listeners, ListenersKey, SweeperManager, SweeperManagerKey};
+use crate::models::notice::NoticeBoard;
+use crate::models::scheduler::run_scheduler;
+use crate::models::shutdown::{drain_and_disconnect, watch_signals, Shutdown};
+use crate::models::store::{DiscordStore, MessageStore};
+use crate::models::sweeper::{run_sweeper, StatsReceiver, Sweeper};
+use crate::models::ttl::TtlReaper;
+use anyhow::{anyhow, bail, Context as _, Result};
+use chrono::Utc;
+use clap::{Parser, Subcommand};
 use models::handler::Handler;
 use models::handler::GENERAL_GROUP;
 use serenity::framework::standard::StandardFramework;
@@ -14,9 +24,11 @@ use serenity::http::Http;
 use serenity::model::id::ChannelId;
 use serenity::prelude::*;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
-use tokio::sync::{watch, OnceCell};
-use tokio::time::{timeout, Timeout};
+use tokio::sync::{Mutex, OnceCell};
+use tokio::time::timeout;
 
 mod commands;
 mod models;
@@ -29,42 +41,139 @@ lazy_static::lazy_static! {
 #[command(name = "billyjoule")]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 struct Args {
-    #[arg(long, env = "GUILD_ID")]
-    guild_id: u64,
+    #[command(subcommand)]
+    command: Command,
+}
 
-    #[arg(long, env = "CHANNEL_ID")]
-    channel_id: u64,
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Connect to discord and run the bot
+    Serve(BotArgs),
+    /// Sweep one channel without connecting to the gateway
+    Sweep(SweepArgs),
+    /// Rebuild the emoji search index from the emoji bucket
+    IndexEmoji(IndexEmojiArgs),
+    /// Check the sweeper config
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Load the sweeper config and report any problems with it
+    Validate(ConfigArgs),
+}
 
+#[derive(Debug, clap::Args)]
+struct ConfigArgs {
     #[arg(
         long,
-        help = "The age of a message before it's deleted",
-        default_value = "1d",
-        value_parser = parse_duration,
+        env = "SWEEPER_CONFIG",
+        help = "Path to the TOML file listing the channels to sweep",
+        default_value = "./sweepers.toml"
     )]
-    max_message_age: Duration,
+    config: PathBuf,
+}
+
+/// What the bot needs to sweep channels, whether it's serving or sweeping once.
+#[derive(Debug, clap::Args)]
+struct BotArgs {
+    #[arg(long, env = "GUILD_ID")]
+    guild_id: u64,
+
+    #[command(flatten)]
+    config: ConfigArgs,
 
     #[arg(
         long,
-        help = "When set, does not actually delete messages",
+        help = "When set, does not actually delete messages in any channel",
         default_value = "false"
     )]
     dry_run: bool,
+
+    #[arg(
+        long,
+        env = "DATABASE_PATH",
+        help = "Path to the SQLite database holding sweep history",
+        default_value = "./billyjoule.db"
+    )]
+    database: PathBuf,
+
+    #[arg(
+        long,
+        env = "REQUESTS_PER_SECOND",
+        help = "Most discord requests a second that sweeps and other background work may make",
+        default_value_t = DEFAULT_REQUESTS_PER_SECOND
+    )]
+    requests_per_second: u32,
+
+    #[arg(
+        long,
+        env = "CONCURRENT_REQUESTS",
+        help = "Most discord requests background work may have in flight at once",
+        default_value_t = DEFAULT_CONCURRENT_REQUESTS
+    )]
+    concurrent_requests: usize,
+}
+
+#[derive(Debug, clap::Args)]
+struct SweepArgs {
+    #[command(flatten)]
+    bot: BotArgs,
+
+    #[arg(
+        long,
+        help = "The channel to sweep, which has to be in the sweeper config"
+    )]
+    channel: u64,
+
+    #[arg(
+        long,
+        help = "Sweep once and exit, instead of sweeping on the channel's schedule"
+    )]
+    once: bool,
 }
 
-fn parse_duration(arg: &str) -> Result<Duration, String> {
-    arg.parse::<DurationString>()
-        .and_then(|ds| Duration::from_std(ds.into()).map_err(|err| err.to_string()))
+#[derive(Debug, clap::Args)]
+struct IndexEmojiArgs {
+    #[arg(long, env = "MEILISEARCH_URL")]
+    meilisearch_url: String,
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     // setup logging
     let _ = dotenv::from_path("./billyjoule.env");
     tracing_subscriber::fmt::init();
 
+    let args = Args::parse();
+    let result = match args.command {
+        Command::Serve(bot) => serve(bot).await,
+        Command::Sweep(sweep_args) => sweep(sweep_args).await,
+        Command::IndexEmoji(index) => {
+            info!("reindexing emoji folder");
+            do_emoji_indexing(index.meilisearch_url)
+                .await
+                .context("failure to index emoji")
+        }
+        Command::Config(ConfigCommand::Validate(config)) => validate_config(config),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn discord_token() -> Result<String> {
+    env::var("DISCORD_TOKEN").context("DISCORD_TOKEN must be set in environment to execute")
+}
+
+async fn serve(args: BotArgs) -> Result<()> {
     // get token
-    let token =
-        env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN must be set in environment to execute");
+    let token = discord_token()?;
 
     let log_channel_id = env::var("LOG_CHANNEL_ID").ok();
 
@@ -82,47 +191,19 @@ async fn main() {
         env!("CARGO_PKG_VERSION"),
         env!("GIT_HASH")
     );
-    // Init sweeper.
-    let args = Args::parse();
-    let http1 = Http::new(&token);
-    let http2 = Http::new(&token);
-    let http3 = Http::new(&token);
-    let mut stats: Vec<watch::Receiver<Stats>> = Vec::new();
-
-    let (sweeper1, stats1) = Sweeper::new(
-        http1,
-        args.guild_id.into(),
-        args.channel_id.into(),
-        args.max_message_age,
-        args.dry_run,
+    // Init sweepers.
+    let config = Config::load(&args.config.config).context("Failed to load sweeper config")?;
+    check_legacy_channel(&config, &args.config.config)?;
+    info!(
+        "Loaded {} swept channel(s) from {}",
+        config.channels.len(),
+        args.config.config.display()
     );
-    stats.push(stats1);
 
-    // Start sweeper.
+    let db = Arc::new(Database::open(&args.database).context("Failed to open database")?);
 
-    let (sweeper2, stats2) = Sweeper::new(
-        http2,
-        args.guild_id.into(),
-        ChannelId(1391119117154517052),
-        args.max_message_age,
-        args.dry_run,
-    );
-    stats.push(stats2);
-
-    let (sweeper3, stats3) = Sweeper::new(
-        http3,
-        args.guild_id.into(),
-        ChannelId(1491124575143067729),
-        args.max_message_age,
-        args.dry_run,
-    );
-    stats.push(stats3);
     // Init handler.
-    let handler = Handler::new(args.guild_id.into(), log_channel_id);
-
-    tokio::spawn(run_sweeper(sweeper3, false));
-    tokio::spawn(run_sweeper(sweeper2, false));
-    tokio::spawn(run_sweeper(sweeper1, false));
+    let handler = Handler::new(args.guild_id.into(), log_channel_id.clone());
 
     let intents = GatewayIntents::GUILDS
         | GatewayIntents::GUILD_MESSAGES
@@ -133,8 +214,7 @@ async fn main() {
         .configure(|c| c.prefix("."))
         .group(&GENERAL_GROUP);
 
-    let mut client;
-    match timeout(
+    let mut client = match timeout(
         core::time::Duration::from_secs(10),
         serenity::Client::builder(&token, intents)
             .framework(framework)
@@ -143,24 +223,140 @@ async fn main() {
     .await
     {
         Ok(Ok(c)) => {
-            client = c;
             info!("Connected to Discord");
+            c
         }
-        Ok(Err(e)) => {
-            error!("Failed to connect to Discord: {:?}", e);
-            return;
-        }
-        Err(e) => {
-            error!("Failed to connect to Discord: {:?}", e);
-            return;
-        }
-    }
+        Ok(Err(e)) => bail!("Failed to connect to Discord: {:?}", e),
+        Err(e) => bail!("Failed to connect to Discord: {:?}", e),
+    };
+
+    // Everything shares the client's connection, so discord's rate limits are tracked in one place.
+    let http = client.cache_and_http.http.clone();
+    RequestBudget::install(args.requests_per_second, args.concurrent_requests);
+
+    // SIGTERM or `.exit` drains what's running, says goodbye and stops the client.
+    let log_channel = log_channel_id
+        .as_deref()
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(ChannelId);
+    tokio::spawn(watch_signals());
+    tokio::spawn(drain_and_disconnect(
+        client.shard_manager.clone(),
+        http.clone(),
+        log_channel,
+    ));
+
+    let store: Arc<dyn MessageStore> = Arc::new(DiscordStore::new(http.clone()));
+    let (reaper, ttl) = TtlReaper::new(store.clone(), db.clone());
+    Shutdown::get().spawn(reaper.run());
+    let (board, notices) = NoticeBoard::new(store.clone(), db.clone());
+    Shutdown::get().spawn(board.run());
+    let listeners = Listeners { ttl, notices };
+
+    let mut manager = SweeperManager::new(
+        store,
+        args.guild_id.into(),
+        args.config.config.clone(),
+        config,
+        args.dry_run,
+        db.clone(),
+        listeners.clone(),
+    );
+    // Start sweepers.
+    let stats = manager.start_all();
+    let waker = manager.waker();
+    let manager = Arc::new(Mutex::new(manager));
+    tokio::spawn(run_scheduler(manager.clone(), waker));
 
     let mut data = client.data.write().await;
     data.insert::<StatsReceiver>(stats);
+    data.insert::<DatabaseKey>(db);
+    data.insert::<SweeperManagerKey>(manager);
+    data.insert::<ListenersKey>(listeners);
     drop(data);
 
     if let Err(why) = client.start().await {
         error!("Client error: {:?}", why);
     }
+    info!("Disconnected, exiting.");
+    Ok(())
+}
+
+/// Sweep one channel over plain HTTP, for running from a cron job while the bot is elsewhere.
+/// Without `--once` it keeps sweeping on the channel's schedule until SIGTERM.
+async fn sweep(args: SweepArgs) -> Result<()> {
+    let config = Config::load(&args.bot.config.config).context("Failed to load sweeper config")?;
+    let channel_id = ChannelId(args.channel);
+    let channel = config.channel(channel_id).ok_or_else(|| {
+        anyhow!(
+            "channel {} isn't in {}",
+            channel_id,
+            args.bot.config.config.display()
+        )
+    })?;
+    if channel.ttl.is_some() {
+        bail!(
+            "channel {} has a ttl, so `serve` deletes its messages as they expire",
+            channel_id
+        );
+    }
+    if channel.paused {
+        warn!("Channel {} is paused, sweeping it anyway.", channel_id);
+    }
+
+    let db = Arc::new(Database::open(&args.bot.database).context("Failed to open database")?);
+    let http = Arc::new(Http::new(&discord_token()?));
+    RequestBudget::install(args.bot.requests_per_second, args.bot.concurrent_requests);
+    tokio::spawn(watch_signals());
+
+    let (mut sweeper, _) = Sweeper::new(
+        Arc::new(DiscordStore::new(http)),
+        args.bot.guild_id.into(),
+        channel,
+        args.bot.dry_run,
+        db,
+    );
+    loop {
+        run_sweeper(&mut sweeper).await;
+        if args.once || Shutdown::get().is_shutting_down() {
+            return Ok(());
+        }
+        let next_run = channel.schedule.next_after(Utc::now());
+        info!("Next sweep of {} at {}", channel_id, next_run);
+        let sleep_for = (next_run - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_for) => {}
+            _ = Shutdown::get().requested() => return Ok(()),
+        }
+    }
+}
+
+fn validate_config(args: ConfigArgs) -> Result<()> {
+    let config = Config::load(&args.config)?;
+    check_legacy_channel(&config, &args.config)?;
+    println!(
+        "{} is valid, {} swept channel(s).",
+        args.config.display(),
+        config.channels.len()
+    );
+    Ok(())
+}
+
+/// The swept channel used to come from `CHANNEL_ID`.  Refuse to run while it's still set to a
+/// channel the config doesn't list, rather than quietly stop sweeping it.
+fn check_legacy_channel(config: &Config, path: &Path) -> Result<()> {
+    let Ok(channel_id) = env::var("CHANNEL_ID") else {
+        return Ok(());
+    };
+    let listed = channel_id
+        .parse::<u64>()
+        .is_ok_and(|id| config.channel(ChannelId(id)).is_some());
+    if !listed {
+        bail!(
+            "CHANNEL_ID is set to {channel_id}, but swept channels come from {} now and it isn't \
+             there. Add a [[channel]] for it, or unset CHANNEL_ID",
+            path.display()
+        );
+    }
+    Ok(())
 }
@@ -0,0 +1,129 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+use serde::Serialize;
+use serenity::model::channel::Message;
+use serenity::model::id::ChannelId;
+use std::collections::BTreeMap;
+use std::env;
+
+/// Prefix that archived messages are stored under in the emoji bucket.  The emoji indexer skips
+/// it so it never shows up as an emoji name.
+pub(crate) const ARCHIVE_PREFIX: &str = "archive";
+
+/// Writes messages the sweeper is about to delete to S3, as JSONL partitioned by channel and day:
+/// `archive/<channel id>/<yyyy-mm-dd>/<run start>-<first message id>.jsonl`.
+pub(crate) struct Archiver {
+    bucket: Bucket,
+}
+
+#[derive(Serialize, Debug)]
+struct ArchivedMessage {
+    id: u64,
+    channel_id: u64,
+    author_id: u64,
+    author: String,
+    bot: bool,
+    content: String,
+    timestamp: DateTime<Utc>,
+    edited_timestamp: Option<DateTime<Utc>>,
+    pinned: bool,
+    attachments: Vec<String>,
+    reactions: Vec<ArchivedReaction>,
+}
+
+#[derive(Serialize, Debug)]
+struct ArchivedReaction {
+    emoji: String,
+    count: u64,
+}
+
+impl From<&Message> for ArchivedMessage {
+    fn from(message: &Message) -> Self {
+        ArchivedMessage {
+            id: message.id.0,
+            channel_id: message.channel_id.0,
+            author_id: message.author.id.0,
+            author: message.author.tag(),
+            bot: message.author.bot,
+            content: message.content.clone(),
+            timestamp: *message.timestamp,
+            edited_timestamp: message.edited_timestamp.map(|t| *t),
+            pinned: message.pinned,
+            attachments: message.attachments.iter().map(|a| a.url.clone()).collect(),
+            reactions: message
+                .reactions
+                .iter()
+                .map(|r| ArchivedReaction {
+                    emoji: r.reaction_type.to_string(),
+                    count: r.count,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Archiver {
+    /// Build an archiver for the bucket the emoji commands use (`EMOJI_S3_ENDPOINT` and
+    /// `EMOJI_S3_BUCKET`).
+    pub(crate) fn from_env() -> Result<Archiver> {
+        let Ok(s3_endpoint) = env::var("EMOJI_S3_ENDPOINT") else {
+            bail!("need an s3 endpoint to archive messages");
+        };
+        let Ok(s3_bucket) = env::var("EMOJI_S3_BUCKET") else {
+            bail!("need a bucket name to archive messages");
+        };
+        let bucket = Bucket::new(
+            &s3_bucket,
+            Region::Custom {
+                region: "us-east-1".to_owned(),
+                endpoint: s3_endpoint,
+            },
+            Credentials::default().context("couldn't load s3 credentials")?,
+        )
+        .context("couldn't open s3 bucket")?
+        .with_path_style();
+        Ok(Archiver { bucket })
+    }
+
+    /// Archive a chunk of messages.  Only returns `Ok` once every object for the chunk has been
+    /// written, so the caller can safely delete the messages afterwards.
+    pub(crate) async fn archive(
+        &self,
+        channel_id: ChannelId,
+        run_started: DateTime<Utc>,
+        messages: &[Message],
+    ) -> Result<()> {
+        let mut partitions: BTreeMap<NaiveDate, Vec<&Message>> = BTreeMap::new();
+        for message in messages {
+            partitions
+                .entry(message.timestamp.date_naive())
+                .or_default()
+                .push(message);
+        }
+
+        for (date, messages) in partitions {
+            let mut body = Vec::new();
+            for message in &messages {
+                serde_json::to_writer(&mut body, &ArchivedMessage::from(*message))?;
+                body.push(b'\n');
+            }
+            let key = format!(
+                "{}/{}/{}/{}-{}.jsonl",
+                ARCHIVE_PREFIX,
+                channel_id.0,
+                date.format("%Y-%m-%d"),
+                run_started.timestamp(),
+                messages[0].id.0
+            );
+            debug!("Archiving {} messages to {}", messages.len(), key);
+            self.bucket
+                .put_object_with_content_type(&key, &body, "application/x-ndjson")
+                .await
+                .with_context(|| format!("couldn't write archive object {key}"))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Discord allows 50 requests a second across the whole bot.  Background work gets less than half
+/// of that unless told otherwise, so commands always have room.
+pub(crate) const DEFAULT_REQUESTS_PER_SECOND: u32 = 20;
+pub(crate) const DEFAULT_CONCURRENT_REQUESTS: usize = 2;
+
+static BUDGET: OnceLock<RequestBudget> = OnceLock::new();
+
+/// How fast the bot's background work (sweeps, purges, the TTL reaper and notices) may make
+/// requests.  Every one of them waits its turn here, while commands answer straight away, so a big
+/// sweep can't use up the rate limit commands need.
+pub(crate) struct RequestBudget {
+    interval: Duration,
+    /// When the next request may start.
+    next: Mutex<Instant>,
+    in_flight: Arc<Semaphore>,
+}
+
+impl RequestBudget {
+    fn new(per_second: u32, concurrent: usize) -> Self {
+        RequestBudget {
+            interval: Duration::from_secs(1) / per_second.max(1),
+            next: Mutex::new(Instant::now()),
+            in_flight: Arc::new(Semaphore::new(concurrent.max(1))),
+        }
+    }
+
+    /// Set the budget for the rest of the run.  Only the first call counts.
+    pub(crate) fn install(per_second: u32, concurrent: usize) {
+        if BUDGET
+            .set(RequestBudget::new(per_second, concurrent))
+            .is_err()
+        {
+            warn!("The request budget was already set, ignoring the new one.");
+        }
+    }
+
+    /// The installed budget, or the default one if nothing was installed.
+    pub(crate) fn get() -> &'static RequestBudget {
+        BUDGET.get_or_init(|| {
+            RequestBudget::new(DEFAULT_REQUESTS_PER_SECOND, DEFAULT_CONCURRENT_REQUESTS)
+        })
+    }
+
+    /// Wait for a turn to make a request.  Hold on to the permit until the request is done.
+    pub(crate) async fn spend(&self) -> OwnedSemaphorePermit {
+        let permit = self
+            .in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the request budget's semaphore is never closed");
+        let start = {
+            let mut next = self.next.lock().await;
+            let start = (*next).max(Instant::now());
+            *next = start + self.interval;
+            start
+        };
+        tokio::time::sleep_until(start).await;
+        permit
+    }
+}
@@ -0,0 +1,523 @@
+use crate::models::rules::{KeepReaction, Rule};
+use crate::models::scheduler::Schedule;
+use anyhow::{bail, Context, Result};
+use chrono::Duration;
+use duration_string::DurationString;
+use serde::{Deserialize, Serialize};
+use serenity::model::id::ChannelId;
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table};
+
+/// The set of channels the bot sweeps, loaded from a TOML file.  Each swept channel is a
+/// `[[channel]]` table, e.g.
+///
+/// ```toml
+/// [[channel]]
+/// id = 1391119117154517052
+/// max_message_age = "1d"
+/// dry_run = false
+/// archive = true
+///
+/// [channel.threads]
+/// sweep = true
+/// max_message_age = "12h"
+/// delete_empty = true
+/// archived = true
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Config {
+    #[serde(default, rename = "channel")]
+    pub(crate) channels: Vec<ChannelConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChannelConfig {
+    pub(crate) id: u64,
+    #[serde(default = "default_max_message_age")]
+    pub(crate) max_message_age: DurationString,
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+    /// Set through `/sweeper pause`; a paused channel keeps its config but isn't swept.
+    #[serde(default)]
+    pub(crate) paused: bool,
+    #[serde(default)]
+    pub(crate) schedule: Schedule,
+    /// Delete each message once it's this old, as it expires, instead of sweeping the channel on
+    /// a schedule.  Pinned messages are still kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ttl: Option<DurationString>,
+    /// Write expiring messages to the emoji S3 bucket before deleting them.
+    #[serde(default)]
+    pub(crate) archive: bool,
+    #[serde(default)]
+    pub(crate) threads: ThreadPolicy,
+    #[serde(default)]
+    pub(crate) old_messages: OldMessagePolicy,
+    /// Retention rules that can keep an expired message or expire one sooner.
+    #[serde(default)]
+    pub(crate) rules: Vec<Rule>,
+    /// A reaction members can use to keep a message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) keep: Option<KeepReaction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) notice: Option<NoticePolicy>,
+    /// Post a summary of what each sweep is about to delete.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) digest: Option<DigestPolicy>,
+}
+
+/// A notice the bot keeps at the bottom of the channel saying how long messages last there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct NoticePolicy {
+    /// Defaults to saying how old messages get before they're deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) text: Option<String>,
+    /// Repost the notice once this many messages have been sent after it.
+    #[serde(default = "default_notice_repost_after")]
+    pub(crate) repost_after: u32,
+    /// Post a heads-up this long before each scheduled sweep.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) warn_before: Option<DurationString>,
+}
+
+fn default_notice_repost_after() -> u32 {
+    20
+}
+
+/// Where digests of a channel's expiring messages are posted.  They're written by the LLM behind
+/// `.llama`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DigestPolicy {
+    pub(crate) channel: u64,
+}
+
+impl DigestPolicy {
+    pub(crate) fn channel_id(&self) -> ChannelId {
+        ChannelId(self.channel)
+    }
+}
+
+/// How threads whose parent is a swept channel are handled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ThreadPolicy {
+    #[serde(default = "default_true")]
+    pub(crate) sweep: bool,
+    /// Falls back to the parent channel's `max_message_age` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_message_age: Option<DurationString>,
+    #[serde(default = "default_true")]
+    pub(crate) delete_empty: bool,
+    /// Also sweep archived threads, unarchiving them for the sweep.
+    #[serde(default = "default_true")]
+    pub(crate) archived: bool,
+}
+
+/// Messages older than Discord's 14-day bulk delete window have to be deleted one at a time.  This
+/// is the budget for doing that, so a backlog left by an outage drains without hogging the rate
+/// limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct OldMessagePolicy {
+    #[serde(default = "default_true")]
+    pub(crate) delete: bool,
+    /// The most old messages deleted in one run; the rest wait for the next run.
+    #[serde(default = "default_old_messages_per_run")]
+    pub(crate) per_run: u32,
+    /// Pause between single deletes.
+    #[serde(default = "default_old_messages_interval")]
+    pub(crate) interval: DurationString,
+}
+
+impl Default for OldMessagePolicy {
+    fn default() -> Self {
+        OldMessagePolicy {
+            delete: true,
+            per_run: default_old_messages_per_run(),
+            interval: default_old_messages_interval(),
+        }
+    }
+}
+
+fn default_old_messages_per_run() -> u32 {
+    500
+}
+
+fn default_old_messages_interval() -> DurationString {
+    DurationString::new(core::time::Duration::from_secs(1))
+}
+
+impl Default for ThreadPolicy {
+    fn default() -> Self {
+        ThreadPolicy {
+            sweep: true,
+            max_message_age: None,
+            delete_empty: true,
+            archived: true,
+        }
+    }
+}
+
+pub(crate) fn default_max_message_age() -> DurationString {
+    DurationString::new(core::time::Duration::from_secs(24 * 60 * 60))
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Convert a `DurationString` from the config file into the chrono duration the sweeper uses.
+pub(crate) fn to_chrono(duration: DurationString) -> Duration {
+    Duration::from_std(duration.into()).unwrap_or(Duration::max_value())
+}
+
+impl Config {
+    /// Read and validate a config file.
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read sweeper config {}", path.display()))?;
+        let config: Config = toml::from_str(&contents)
+            .with_context(|| format!("couldn't parse sweeper config {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Write the config back out, so changes made at runtime survive a restart.  Only the settings
+    /// that changed are rewritten; the rest of the file, comments included, is left as it was.
+    pub(crate) fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("couldn't read sweeper config {}", path.display()))
+            }
+        };
+        let mut doc: DocumentMut = contents
+            .parse()
+            .with_context(|| format!("couldn't parse sweeper config {}", path.display()))?;
+        self.update_document(&mut doc)?;
+        fs::write(path, doc.to_string())
+            .with_context(|| format!("couldn't write sweeper config {}", path.display()))
+    }
+
+    /// Bring a parsed config file in line with this config.  Channels that are gone are removed,
+    /// new ones are added at the end, and only the keys that changed are touched in the rest.
+    fn update_document(&self, doc: &mut DocumentMut) -> Result<()> {
+        let mut next_position = last_position(doc.as_table()) + 1;
+        let tables = doc
+            .entry("channel")
+            .or_insert(Item::ArrayOfTables(ArrayOfTables::new()))
+            .as_array_of_tables_mut()
+            .context("`channel` in the sweeper config isn't a list of [[channel]] tables")?;
+        tables.retain(|table| {
+            table_id(table).is_some_and(|id| self.channel(ChannelId(id)).is_some())
+        });
+        for channel in &self.channels {
+            let new = to_table(channel)?;
+            let index = tables
+                .iter()
+                .position(|table| table_id(table) == Some(channel.id));
+            match index.and_then(|i| tables.get_mut(i)) {
+                Some(table) => {
+                    // compare like with like: the file's settings as they'd be written out.
+                    let old: ChannelConfig =
+                        toml::from_str(&table.to_string()).with_context(|| {
+                            format!(
+                                "couldn't parse channel {} in the sweeper config",
+                                channel.id
+                            )
+                        })?;
+                    let position = table.position().unwrap_or(next_position);
+                    merge(table, &to_table(&old)?, new, position);
+                }
+                None => {
+                    let mut table = new;
+                    place(&mut table, next_position);
+                    next_position += 1;
+                    tables.push(table);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn channel(&self, channel_id: ChannelId) -> Option<&ChannelConfig> {
+        self.channels.iter().find(|c| c.id == channel_id.0)
+    }
+
+    pub(crate) fn channel_mut(&mut self, channel_id: ChannelId) -> Option<&mut ChannelConfig> {
+        self.channels.iter_mut().find(|c| c.id == channel_id.0)
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        let mut seen = HashSet::new();
+        for channel in &self.channels {
+            if !seen.insert(channel.id) {
+                bail!("channel {} is configured more than once", channel.id);
+            }
+            if channel.max_message_age() <= Duration::zero() {
+                bail!("channel {} has a max_message_age of zero", channel.id);
+            }
+            channel
+                .schedule
+                .validate()
+                .with_context(|| format!("channel {} has a bad schedule", channel.id))?;
+            if let Some(ttl) = channel.ttl {
+                if to_chrono(ttl) <= Duration::zero() {
+                    bail!("channel {} has a ttl of zero", channel.id);
+                }
+                if channel.archive
+                    || !channel.rules.is_empty()
+                    || channel.keep.is_some()
+                    || channel.digest.is_some()
+                {
+                    bail!(
+                        "channel {} has a ttl, which can't be combined with archive, rules, keep \
+                         or digest",
+                        channel.id
+                    );
+                }
+            }
+            if channel.notice.as_ref().is_some_and(|n| n.repost_after == 0) {
+                bail!("channel {} has a notice repost_after of zero", channel.id);
+            }
+            if channel.threads.max_message_age.map(to_chrono) == Some(Duration::zero()) {
+                bail!(
+                    "channel {} has a thread max_message_age of zero",
+                    channel.id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The channel id of a `[[channel]]` table in the config file.
+fn table_id(table: &Table) -> Option<u64> {
+    table.get("id")?.as_integer()?.try_into().ok()
+}
+
+/// A channel's settings as a table, written out the way `toml` writes them.
+fn to_table(channel: &ChannelConfig) -> Result<Table> {
+    let doc: DocumentMut = toml::to_string(channel)
+        .context("couldn't serialize sweeper config")?
+        .parse()
+        .context("couldn't serialize sweeper config")?;
+    Ok(doc.as_table().clone())
+}
+
+/// Change `table` from the settings in `old` to the ones in `new`, leaving the keys that are the
+/// same in both alone so they keep their formatting and comments.  Tables that have to be added
+/// are written out at `position`, alongside the table they belong to.
+fn merge(table: &mut Table, old: &Table, new: Table, position: usize) {
+    for (key, _) in old.iter() {
+        if !new.contains_key(key) {
+            table.remove(key);
+        }
+    }
+    for (key, mut item) in new {
+        let old_item = old.get(&key);
+        if old_item.is_some_and(|old| old.to_string() == item.to_string()) {
+            continue;
+        }
+        let Some(existing) = table.get_mut(&key) else {
+            place_item(&mut item, position);
+            table.insert(&key, item);
+            continue;
+        };
+        match (existing, old_item, item) {
+            (Item::Table(existing), Some(Item::Table(old)), Item::Table(new)) => {
+                merge(existing, old, new, position)
+            }
+            (Item::Value(existing), _, Item::Value(new)) => {
+                let decor = existing.decor().clone();
+                *existing = new;
+                *existing.decor_mut() = decor;
+            }
+            (existing, _, mut item) => {
+                place_item(&mut item, position);
+                *existing = item;
+            }
+        }
+    }
+}
+
+/// Put a table, and any tables inside it, at `position` in the file.
+fn place(table: &mut Table, position: usize) {
+    table.set_position(position);
+    for (_, item) in table.iter_mut() {
+        place_item(item, position);
+    }
+}
+
+fn place_item(item: &mut Item, position: usize) {
+    match item {
+        Item::Table(table) => place(table, position),
+        Item::ArrayOfTables(tables) => tables.iter_mut().for_each(|t| place(t, position)),
+        _ => {}
+    }
+}
+
+/// The position of the last table in the file.
+fn last_position(table: &Table) -> usize {
+    table.iter().fold(
+        table.position().unwrap_or(0),
+        |last, (_, item)| match item {
+            Item::Table(table) => last.max(last_position(table)),
+            Item::ArrayOfTables(tables) => tables.iter().map(last_position).fold(last, usize::max),
+            _ => last,
+        },
+    )
+}
+
+impl ChannelConfig {
+    pub(crate) fn new(
+        channel_id: ChannelId,
+        max_message_age: DurationString,
+        dry_run: bool,
+    ) -> Self {
+        ChannelConfig {
+            id: channel_id.0,
+            max_message_age,
+            dry_run,
+            paused: false,
+            schedule: Schedule::default(),
+            ttl: None,
+            archive: false,
+            threads: ThreadPolicy::default(),
+            old_messages: OldMessagePolicy::default(),
+            rules: vec![],
+            keep: None,
+            notice: None,
+            digest: None,
+        }
+    }
+
+    pub(crate) fn channel_id(&self) -> ChannelId {
+        ChannelId(self.id)
+    }
+
+    pub(crate) fn max_message_age(&self) -> Duration {
+        to_chrono(self.max_message_age)
+    }
+
+    /// What the channel's notice says, if it has one.
+    pub(crate) fn notice_text(&self) -> Option<String> {
+        let notice = self.notice.as_ref()?;
+        Some(notice.text.clone().unwrap_or_else(|| {
+            format!(
+                "Messages in this channel are deleted after {}.",
+                self.ttl.unwrap_or(self.max_message_age)
+            )
+        }))
+    }
+
+    /// The config used to sweep one of this channel's threads.  Threads of threads don't exist,
+    /// so thread sweeping is switched off for it.
+    pub(crate) fn thread_config(&self, thread_id: ChannelId) -> ChannelConfig {
+        ChannelConfig {
+            id: thread_id.0,
+            max_message_age: self.threads.max_message_age.unwrap_or(self.max_message_age),
+            dry_run: self.dry_run,
+            paused: false,
+            schedule: self.schedule.clone(),
+            ttl: None,
+            archive: self.archive,
+            threads: ThreadPolicy {
+                sweep: false,
+                ..ThreadPolicy::default()
+            },
+            old_messages: self.old_messages.clone(),
+            rules: self.rules.clone(),
+            keep: self.keep.clone(),
+            notice: None,
+            digest: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMENTED: &str = r#"# channels the bot sweeps
+
+# general chat
+[[channel]]
+id = 1
+max_message_age = "1d" # a day is plenty
+
+# threads go faster
+[channel.threads]
+sweep = true
+max_message_age = "12h"
+
+# art, kept for a week
+[[channel]]
+id = 2
+max_message_age = "7d"
+"#;
+
+    fn saved(config: &Config) -> String {
+        let mut doc: DocumentMut = COMMENTED.parse().unwrap();
+        config.update_document(&mut doc).unwrap();
+        doc.to_string()
+    }
+
+    #[test]
+    fn saving_an_unchanged_config_changes_nothing() {
+        let config: Config = toml::from_str(COMMENTED).unwrap();
+        assert_eq!(saved(&config), COMMENTED);
+    }
+
+    #[test]
+    fn saving_only_touches_what_changed() {
+        let mut config: Config = toml::from_str(COMMENTED).unwrap();
+        let general = config.channel_mut(ChannelId(1)).unwrap();
+        general.max_message_age = DurationString::new(core::time::Duration::from_secs(2 * 86400));
+        general.paused = true;
+        config.channels.retain(|c| c.id != 2);
+        config.channels.push(ChannelConfig::new(
+            ChannelId(3),
+            DurationString::new(core::time::Duration::from_secs(3600)),
+            true,
+        ));
+
+        let saved = saved(&config);
+        let (kept, added) = saved.split_at(saved.find("[[channel]]\nid = 3").unwrap());
+        assert_eq!(
+            kept,
+            r#"# channels the bot sweeps
+
+# general chat
+[[channel]]
+id = 1
+max_message_age = "2d" # a day is plenty
+paused = true
+
+# threads go faster
+[channel.threads]
+sweep = true
+max_message_age = "12h"
+
+"#
+        );
+        assert!(added.contains("dry_run = true"));
+        let reloaded: Config = toml::from_str(&saved).unwrap();
+        let ids: Vec<u64> = reloaded.channels.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert!(reloaded.channels[0].paused);
+        assert_eq!(
+            reloaded.channels[0]
+                .threads
+                .max_message_age
+                .unwrap()
+                .to_string(),
+            "12h"
+        );
+    }
+}
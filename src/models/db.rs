@@ -0,0 +1,503 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serenity::model::id::{ChannelId, MessageId, UserId};
+use serenity::prelude::TypeMapKey;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The bot's local store.  Queries are small, so a single connection behind a mutex is plenty.
+pub(crate) struct Database {
+    conn: Mutex<Connection>,
+}
+
+pub(crate) struct DatabaseKey;
+
+impl TypeMapKey for DatabaseKey {
+    type Value = Arc<Database>;
+}
+
+/// What one sweep of a channel did.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SweepRun {
+    pub(crate) scanned: u32,
+    pub(crate) deleted: u32,
+    pub(crate) pinned: u32,
+    pub(crate) errors: u32,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RunRecord {
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) finished_at: Option<DateTime<Utc>>,
+    pub(crate) run: SweepRun,
+}
+
+/// Lifetime totals for a channel, across every recorded run.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Totals {
+    pub(crate) runs: u32,
+    pub(crate) first_run: Option<DateTime<Utc>>,
+    pub(crate) run: SweepRun,
+}
+
+/// A message the bot deleted, and why.
+#[derive(Debug, Clone)]
+pub(crate) struct Deletion {
+    pub(crate) message_id: MessageId,
+    pub(crate) channel_id: ChannelId,
+    /// Unknown for messages the bot only ever saw the id of.
+    pub(crate) author_id: Option<UserId>,
+    pub(crate) sent_at: DateTime<Utc>,
+    pub(crate) reason: String,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DeletionRecord {
+    pub(crate) deletion: Deletion,
+    pub(crate) deleted_at: DateTime<Utc>,
+    pub(crate) run_id: Option<i64>,
+}
+
+/// Which deletions `/sweeper audit` is asking about.  Unset fields match everything; the time range
+/// is on when the message was sent.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditFilter {
+    pub(crate) user: Option<UserId>,
+    pub(crate) channel: Option<ChannelId>,
+    pub(crate) sent_after: Option<DateTime<Utc>>,
+    pub(crate) sent_before: Option<DateTime<Utc>>,
+}
+
+impl AuditFilter {
+    /// Only match messages sent from the start of `from` through the end of `to`, in UTC.
+    pub(crate) fn days(self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> AuditFilter {
+        let midnight = |day: NaiveDate| day.and_time(NaiveTime::MIN).and_utc();
+        AuditFilter {
+            sent_after: from.map(midnight),
+            sent_before: to.and_then(|d| d.succ_opt()).map(midnight),
+            ..self
+        }
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sweep_runs (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    channel_id  INTEGER NOT NULL,
+    started_at  TEXT NOT NULL,
+    finished_at TEXT,
+    scanned     INTEGER NOT NULL DEFAULT 0,
+    deleted     INTEGER NOT NULL DEFAULT 0,
+    pinned      INTEGER NOT NULL DEFAULT 0,
+    errors      INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS sweep_runs_channel ON sweep_runs (channel_id, started_at);
+CREATE TABLE IF NOT EXISTS deletions (
+    message_id  INTEGER PRIMARY KEY,
+    channel_id  INTEGER NOT NULL,
+    author_id   INTEGER,
+    sent_at     TEXT NOT NULL,
+    deleted_at  TEXT NOT NULL,
+    reason      TEXT NOT NULL,
+    run_id      INTEGER REFERENCES sweep_runs (id)
+);
+CREATE INDEX IF NOT EXISTS deletions_author ON deletions (author_id, sent_at);
+CREATE INDEX IF NOT EXISTS deletions_channel ON deletions (channel_id, sent_at);
+CREATE TABLE IF NOT EXISTS protected_messages (
+    message_id  INTEGER PRIMARY KEY,
+    channel_id  INTEGER NOT NULL,
+    kind        TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS old_messages (
+    message_id  INTEGER PRIMARY KEY,
+    channel_id  INTEGER NOT NULL,
+    author_id   INTEGER,
+    sent_at     TEXT NOT NULL,
+    reason      TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS old_messages_channel ON old_messages (channel_id);
+";
+
+impl Database {
+    /// Open (or create) the database and bring its schema up to date.
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Database> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .with_context(|| format!("couldn't open database {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .context("couldn't create database schema")?;
+        Ok(Database {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // a panic while holding the lock can't leave a rusqlite connection half-written.
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record the start of a sweep, returning the run id.
+    pub(crate) fn start_run(
+        &self,
+        channel_id: ChannelId,
+        started_at: DateTime<Utc>,
+    ) -> Result<i64> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO sweep_runs (channel_id, started_at) VALUES (?1, ?2)",
+            params![channel_id.0 as i64, started_at],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub(crate) fn finish_run(
+        &self,
+        run_id: i64,
+        finished_at: DateTime<Utc>,
+        run: &SweepRun,
+    ) -> Result<()> {
+        self.conn().execute(
+            "UPDATE sweep_runs
+             SET finished_at = ?2, scanned = ?3, deleted = ?4, pinned = ?5, errors = ?6
+             WHERE id = ?1",
+            params![
+                run_id,
+                finished_at,
+                run.scanned,
+                run.deleted,
+                run.pinned,
+                run.errors
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn totals(&self, channel_id: ChannelId) -> Result<Totals> {
+        let totals = self
+            .conn()
+            .query_row(
+                "SELECT COUNT(*), MIN(started_at), SUM(scanned), SUM(deleted), SUM(pinned),
+                        SUM(errors)
+                 FROM sweep_runs WHERE channel_id = ?1",
+                params![channel_id.0 as i64],
+                |row| {
+                    Ok(Totals {
+                        runs: row.get(0)?,
+                        first_run: row.get(1)?,
+                        run: SweepRun {
+                            scanned: row.get::<_, Option<u32>>(2)?.unwrap_or(0),
+                            deleted: row.get::<_, Option<u32>>(3)?.unwrap_or(0),
+                            pinned: row.get::<_, Option<u32>>(4)?.unwrap_or(0),
+                            errors: row.get::<_, Option<u32>>(5)?.unwrap_or(0),
+                        },
+                    })
+                },
+            )
+            .optional()?;
+        Ok(totals.unwrap_or_default())
+    }
+
+    /// The channel's most recent runs, newest first.
+    pub(crate) fn recent_runs(&self, channel_id: ChannelId, limit: u32) -> Result<Vec<RunRecord>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT started_at, finished_at, scanned, deleted, pinned, errors
+             FROM sweep_runs WHERE channel_id = ?1
+             ORDER BY started_at DESC LIMIT ?2",
+        )?;
+        let runs = stmt
+            .query_map(params![channel_id.0 as i64, limit], |row| {
+                Ok(RunRecord {
+                    started_at: row.get(0)?,
+                    finished_at: row.get(1)?,
+                    run: SweepRun {
+                        scanned: row.get(2)?,
+                        deleted: row.get(3)?,
+                        pinned: row.get(4)?,
+                        errors: row.get(5)?,
+                    },
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(runs)
+    }
+
+    /// Record messages that were just deleted.
+    pub(crate) fn record_deletions(
+        &self,
+        run_id: Option<i64>,
+        deleted_at: DateTime<Utc>,
+        deletions: &[Deletion],
+    ) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO deletions
+                 (message_id, channel_id, author_id, sent_at, deleted_at, reason, run_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for deletion in deletions {
+                stmt.execute(params![
+                    deletion.message_id.0 as i64,
+                    deletion.channel_id.0 as i64,
+                    deletion.author_id.map(|u| u.0 as i64),
+                    deletion.sent_at,
+                    deleted_at,
+                    deletion.reason,
+                    run_id
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletions matching the filter, most recently sent first.
+    pub(crate) fn deletions(
+        &self,
+        filter: &AuditFilter,
+        limit: u32,
+    ) -> Result<Vec<DeletionRecord>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT message_id, channel_id, author_id, sent_at, reason, deleted_at, run_id
+             FROM deletions
+             WHERE (?1 IS NULL OR author_id = ?1)
+               AND (?2 IS NULL OR channel_id = ?2)
+               AND (?3 IS NULL OR sent_at >= ?3)
+               AND (?4 IS NULL OR sent_at < ?4)
+             ORDER BY sent_at DESC LIMIT ?5",
+        )?;
+        let records = stmt
+            .query_map(
+                params![
+                    filter.user.map(|u| u.0 as i64),
+                    filter.channel.map(|c| c.0 as i64),
+                    filter.sent_after,
+                    filter.sent_before,
+                    limit
+                ],
+                |row| {
+                    Ok(DeletionRecord {
+                        deletion: Deletion {
+                            message_id: MessageId(row.get::<_, i64>(0)? as u64),
+                            channel_id: ChannelId(row.get::<_, i64>(1)? as u64),
+                            author_id: row.get::<_, Option<i64>>(2)?.map(|u| UserId(u as u64)),
+                            sent_at: row.get(3)?,
+                            reason: row.get(4)?,
+                        },
+                        deleted_at: row.get(5)?,
+                        run_id: row.get(6)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+
+    /// Keep one of the bot's own messages (a channel notice, say) safe from sweeping.
+    pub(crate) fn protect(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        kind: &str,
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO protected_messages (message_id, channel_id, kind)
+             VALUES (?1, ?2, ?3)",
+            params![message_id.0 as i64, channel_id.0 as i64, kind],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn unprotect(&self, message_id: MessageId) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM protected_messages WHERE message_id = ?1",
+            params![message_id.0 as i64],
+        )?;
+        Ok(())
+    }
+
+    /// The channel's protected messages, with what kind of message each is.
+    pub(crate) fn protected_messages(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<Vec<(MessageId, String)>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT message_id, kind FROM protected_messages WHERE channel_id = ?1")?;
+        let messages = stmt
+            .query_map(params![channel_id.0 as i64], |row| {
+                Ok((MessageId(row.get::<_, i64>(0)? as u64), row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(messages)
+    }
+
+    /// Queue messages too old for bulk delete to be deleted one at a time.
+    pub(crate) fn queue_old_messages(&self, deletions: &[Deletion]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO old_messages
+                 (message_id, channel_id, author_id, sent_at, reason)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for deletion in deletions {
+                stmt.execute(params![
+                    deletion.message_id.0 as i64,
+                    deletion.channel_id.0 as i64,
+                    deletion.author_id.map(|u| u.0 as i64),
+                    deletion.sent_at,
+                    deletion.reason
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Take a message off the queue of old messages, once it's deleted or can't ever be.
+    pub(crate) fn unqueue_old_message(&self, message_id: MessageId) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM old_messages WHERE message_id = ?1",
+            params![message_id.0 as i64],
+        )?;
+        Ok(())
+    }
+
+    /// The channel's queued old messages.
+    pub(crate) fn old_messages(&self, channel_id: ChannelId) -> Result<Vec<Deletion>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT message_id, channel_id, author_id, sent_at, reason
+             FROM old_messages WHERE channel_id = ?1",
+        )?;
+        let deletions = stmt
+            .query_map(params![channel_id.0 as i64], |row| {
+                Ok(Deletion {
+                    message_id: MessageId(row.get::<_, i64>(0)? as u64),
+                    channel_id: ChannelId(row.get::<_, i64>(1)? as u64),
+                    author_id: row.get::<_, Option<i64>>(2)?.map(|u| UserId(u as u64)),
+                    sent_at: row.get(3)?,
+                    reason: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(deletions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: UserId = UserId(1);
+    const BOB: UserId = UserId(2);
+    const GENERAL: ChannelId = ChannelId(10);
+    const MEMES: ChannelId = ChannelId(20);
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn day(s: &str) -> Option<NaiveDate> {
+        Some(NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap())
+    }
+
+    fn deletion(id: u64, channel_id: ChannelId, author: UserId, sent_at: &str) -> Deletion {
+        Deletion {
+            message_id: MessageId(id),
+            channel_id,
+            author_id: Some(author),
+            sent_at: utc(sent_at),
+            reason: "max_message_age(1day)".to_string(),
+        }
+    }
+
+    /// Alice and Bob each post in both channels, either side of midnight on 2024-01-15.
+    fn audit_log() -> Database {
+        let db = Database::open(":memory:").unwrap();
+        db.record_deletions(
+            None,
+            utc("2024-01-20T00:00:00Z"),
+            &[
+                deletion(1, GENERAL, ALICE, "2024-01-14T23:59:59Z"),
+                deletion(2, GENERAL, ALICE, "2024-01-15T00:00:00Z"),
+                deletion(3, MEMES, ALICE, "2024-01-15T23:59:59Z"),
+                deletion(4, GENERAL, BOB, "2024-01-16T00:00:00Z"),
+                deletion(5, MEMES, BOB, "2024-01-16T12:00:00Z"),
+            ],
+        )
+        .unwrap();
+        db
+    }
+
+    fn ids(db: &Database, filter: AuditFilter) -> Vec<u64> {
+        db.deletions(&filter, 100)
+            .unwrap()
+            .iter()
+            .map(|r| r.deletion.message_id.0)
+            .collect()
+    }
+
+    #[test]
+    fn an_empty_filter_matches_everything_newest_first() {
+        assert_eq!(ids(&audit_log(), AuditFilter::default()), [5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn audits_filter_by_user_and_channel() {
+        let db = audit_log();
+        let filter = |user, channel| AuditFilter {
+            user,
+            channel,
+            ..AuditFilter::default()
+        };
+        assert_eq!(ids(&db, filter(Some(ALICE), None)), [3, 2, 1]);
+        assert_eq!(ids(&db, filter(None, Some(MEMES))), [5, 3]);
+        assert_eq!(ids(&db, filter(Some(BOB), Some(GENERAL))), [4]);
+        assert_eq!(ids(&db, filter(Some(UserId(3)), None)), [0u64; 0]);
+    }
+
+    #[test]
+    fn a_day_range_covers_whole_days_in_utc() {
+        let db = audit_log();
+        let days = |from, to| AuditFilter::default().days(day(from), day(to));
+        assert_eq!(ids(&db, days("2024-01-15", "2024-01-15")), [3, 2]);
+        assert_eq!(ids(&db, days("2024-01-15", "2024-01-16")), [5, 4, 3, 2]);
+        assert_eq!(ids(&db, days("2024-01-16", "2024-01-15")), [0u64; 0]);
+    }
+
+    #[test]
+    fn either_end_of_a_day_range_can_be_left_open() {
+        let db = audit_log();
+        let from = AuditFilter::default().days(day("2024-01-16"), None);
+        assert_eq!(ids(&db, from), [5, 4]);
+        let to = AuditFilter::default().days(None, day("2024-01-14"));
+        assert_eq!(ids(&db, to), [1]);
+    }
+
+    #[test]
+    fn day_ranges_combine_with_the_other_filters() {
+        let db = audit_log();
+        let filter = AuditFilter {
+            user: Some(ALICE),
+            channel: Some(GENERAL),
+            ..AuditFilter::default()
+        }
+        .days(day("2024-01-15"), day("2024-01-16"));
+        assert_eq!(ids(&db, filter), [2]);
+    }
+
+    #[test]
+    fn audits_stop_at_the_limit() {
+        let records = audit_log().deletions(&AuditFilter::default(), 2).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].deletion.message_id, MessageId(5));
+        assert_eq!(records[0].deleted_at, utc("2024-01-20T00:00:00Z"));
+    }
+}
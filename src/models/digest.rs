@@ -0,0 +1,104 @@
+use crate::commands::llama::{OllamaApi, DISCORD_MSG_SIZE_LIMIT};
+use anyhow::Result;
+use serenity::model::channel::Message;
+
+/// The most transcript sent to the model in one go.  A character is roughly a quarter of a token,
+/// so this leaves room in a 2048 token context for the prompt and the summary.
+const DIGEST_CHUNK_CHARS: usize = 6000;
+
+/// Summarize messages, oldest first.  A transcript too long for the model's context is summarized
+/// a chunk at a time, then the summaries are summarized together.
+pub(crate) async fn write_digest(api: &OllamaApi, messages: &[&Message]) -> Result<String> {
+    let lines = messages.iter().map(|m| transcript_line(m)).collect();
+    let mut chunks = pack(lines, DIGEST_CHUNK_CHARS);
+    loop {
+        let mut summaries = vec![];
+        for chunk in chunks.iter() {
+            summaries.push(api.summarize(chunk.clone()).await?);
+        }
+        if summaries.len() == 1 {
+            return Ok(summaries.remove(0));
+        }
+        let packed = pack(summaries, DIGEST_CHUNK_CHARS);
+        // summaries too long to pack any tighter would go round forever, so keep what fits.
+        chunks = match packed.len() < chunks.len() {
+            true => packed,
+            false => packed.into_iter().take(1).collect(),
+        };
+    }
+}
+
+/// One message as a line of transcript: when it was sent, who sent it and what they said.
+fn transcript_line(message: &Message) -> String {
+    let mut line = format!(
+        "[{} UTC] {}: {}",
+        message.timestamp.format("%H:%M"),
+        message.author.name,
+        message.content.replace('\n', " ")
+    );
+    if !message.attachments.is_empty() {
+        line.push_str(" [attachment]");
+    }
+    line
+}
+
+/// Pack lines into newline-separated chunks of at most `max_chars`.  A line that's too long on its
+/// own is cut short.
+fn pack(lines: Vec<String>, max_chars: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for mut line in lines {
+        if line.len() > max_chars {
+            let mut end = max_chars;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+        if !chunk.is_empty() && chunk.len() + 1 + line.len() > max_chars {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        if !chunk.is_empty() {
+            chunk.push('\n');
+        }
+        chunk.push_str(&line);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Split a digest into discord messages, breaking between lines where it can.
+pub(crate) fn split_for_discord(text: &str) -> Vec<String> {
+    pack(
+        text.lines().map(str::to_string).collect(),
+        DISCORD_MSG_SIZE_LIMIT,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_fills_chunks_without_splitting_lines() {
+        let lines: Vec<String> = (0..10).map(|i| format!("line {i:02}")).collect();
+        let chunks = pack(lines, 24);
+        assert_eq!(
+            chunks,
+            vec![
+                "line 00\nline 01\nline 02",
+                "line 03\nline 04\nline 05",
+                "line 06\nline 07\nline 08",
+                "line 09",
+            ]
+        );
+    }
+
+    #[test]
+    fn pack_cuts_lines_longer_than_a_chunk() {
+        let chunks = pack(vec!["é".repeat(10), "short".to_string()], 5);
+        assert_eq!(chunks, vec!["éé", "short"]);
+    }
+}
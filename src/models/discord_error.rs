@@ -0,0 +1,235 @@
+use crate::models::budget::RequestBudget;
+use serenity::http::HttpError;
+use serenity::model::ModelError;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// How many times a request that failed with a retryable error is tried again.
+const MAX_RETRIES: u32 = 3;
+/// The first pause before a retry, doubled after each one.
+const RETRY_BACKOFF_MILLIS: u64 = 500;
+
+/// The discord errors the bot runs into, sorted out of serenity's error so callers can decide what
+/// to do without matching on JSON error codes, and so the log channel gets something short.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DiscordError {
+    /// 10003: the channel was deleted, or the bot can't see it.
+    UnknownChannel,
+    /// 10007: the member has left the guild.
+    UnknownMember,
+    /// 10008: the message is already gone.
+    UnknownMessage,
+    /// 30008: the server has no emoji slots left.
+    MaxEmojis,
+    /// 50001: the bot can't see the channel.
+    MissingAccess,
+    /// 50013: the bot lacks a permission it needs.
+    MissingPermissions,
+    /// 50021: discord won't let anyone delete system messages.
+    SystemMessage,
+    /// 50034: a message in a bulk delete is more than two weeks old.
+    TooOldToBulkDelete,
+    /// 50083: the thread is archived and has to be unarchived first.
+    ThreadArchived,
+    /// A 429 that got past serenity's rate limiter.
+    RateLimited,
+    /// A 5xx, or the request never made it to discord.
+    Unavailable(String),
+    /// Anything else discord said no to.
+    Other { code: isize, message: String },
+    /// An error that didn't come from discord at all.
+    Client(String),
+}
+
+/// What to do about an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorPolicy {
+    /// Try the same request again shortly.
+    Retry,
+    /// Give up on this item and carry on with the rest.
+    Skip,
+    /// Stop, trying again won't help until something changes.
+    Abort,
+}
+
+impl DiscordError {
+    pub(crate) fn policy(&self) -> ErrorPolicy {
+        match self {
+            DiscordError::RateLimited | DiscordError::Unavailable(_) => ErrorPolicy::Retry,
+            DiscordError::UnknownMember
+            | DiscordError::UnknownMessage
+            | DiscordError::SystemMessage
+            | DiscordError::TooOldToBulkDelete => ErrorPolicy::Skip,
+            // nothing in the thread can be deleted, one at a time or otherwise.
+            DiscordError::ThreadArchived
+            | DiscordError::UnknownChannel
+            | DiscordError::MaxEmojis
+            | DiscordError::MissingAccess
+            | DiscordError::MissingPermissions
+            | DiscordError::Other { .. }
+            | DiscordError::Client(_) => ErrorPolicy::Abort,
+        }
+    }
+
+    /// Whether the bot is set up wrong, so a person has to fix something before it'll work.
+    pub(crate) fn is_misconfiguration(&self) -> bool {
+        matches!(
+            self,
+            DiscordError::MissingAccess | DiscordError::MissingPermissions
+        )
+    }
+}
+
+impl From<&serenity::Error> for DiscordError {
+    fn from(error: &serenity::Error) -> Self {
+        match error {
+            serenity::Error::Http(http) => match http.as_ref() {
+                HttpError::UnsuccessfulRequest(response) => {
+                    let status = response.status_code.as_u16();
+                    match response.error.code {
+                        10003 => DiscordError::UnknownChannel,
+                        10007 => DiscordError::UnknownMember,
+                        10008 => DiscordError::UnknownMessage,
+                        30008 => DiscordError::MaxEmojis,
+                        50001 => DiscordError::MissingAccess,
+                        50013 => DiscordError::MissingPermissions,
+                        50021 => DiscordError::SystemMessage,
+                        50034 => DiscordError::TooOldToBulkDelete,
+                        50083 => DiscordError::ThreadArchived,
+                        _ if status == 429 => DiscordError::RateLimited,
+                        _ if status >= 500 => {
+                            DiscordError::Unavailable(format!("discord returned {status}"))
+                        }
+                        code => DiscordError::Other {
+                            code,
+                            message: response.error.message.clone(),
+                        },
+                    }
+                }
+                HttpError::Request(e) => DiscordError::Unavailable(e.to_string()),
+                e => DiscordError::Client(e.to_string()),
+            },
+            serenity::Error::Model(ModelError::InvalidPermissions(_)) => {
+                DiscordError::MissingPermissions
+            }
+            e => DiscordError::Client(e.to_string()),
+        }
+    }
+}
+
+impl From<serenity::Error> for DiscordError {
+    fn from(error: serenity::Error) -> Self {
+        DiscordError::from(&error)
+    }
+}
+
+impl fmt::Display for DiscordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscordError::UnknownChannel => write!(f, "the channel doesn't exist"),
+            DiscordError::UnknownMember => write!(f, "the member isn't in the server"),
+            DiscordError::UnknownMessage => write!(f, "the message is already gone"),
+            DiscordError::MaxEmojis => write!(f, "the server is out of emoji slots"),
+            DiscordError::MissingAccess => write!(f, "the bot can't see the channel"),
+            DiscordError::MissingPermissions => write!(f, "the bot is missing permissions"),
+            DiscordError::SystemMessage => write!(f, "system messages can't be deleted"),
+            DiscordError::TooOldToBulkDelete => {
+                write!(f, "messages over two weeks old can't be bulk deleted")
+            }
+            DiscordError::ThreadArchived => write!(f, "the thread is archived"),
+            DiscordError::RateLimited => write!(f, "rate limited by discord"),
+            DiscordError::Unavailable(reason) => write!(f, "discord is unavailable: {reason}"),
+            DiscordError::Other { code, message } => write!(f, "{message} ({code})"),
+            DiscordError::Client(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DiscordError {}
+
+/// Make a background request, trying again with a backoff while it fails with an error whose
+/// policy is to retry.  Any other error is returned straight away.  Every attempt spends from the
+/// request budget.
+pub(crate) async fn with_retry<T, F, Fut>(mut request: F) -> Result<T, DiscordError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = serenity::Result<T>>,
+{
+    let mut backoff = Duration::from_millis(RETRY_BACKOFF_MILLIS);
+    let mut retries = 0;
+    loop {
+        let permit = RequestBudget::get().spend().await;
+        let result = request().await;
+        drop(permit);
+        match result {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                let error = DiscordError::from(e);
+                if error.policy() != ErrorPolicy::Retry || retries == MAX_RETRIES {
+                    return Err(error);
+                }
+                warn!("Retrying discord request in {backoff:?}: {error}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::http::error::ErrorResponse;
+    use serenity::http::StatusCode;
+
+    fn discord_error(status: u16, code: isize) -> DiscordError {
+        let response = ErrorResponse {
+            status_code: StatusCode::from_u16(status).unwrap(),
+            url: "https://discord.com/api/v10/channels/1/messages"
+                .parse()
+                .unwrap(),
+            error: serde_json::from_value(serde_json::json!({
+                "code": code,
+                "message": "nope",
+            }))
+            .unwrap(),
+        };
+        let error = serenity::Error::Http(Box::new(HttpError::UnsuccessfulRequest(response)));
+        DiscordError::from(error)
+    }
+
+    #[test]
+    fn json_codes_are_sorted_out() {
+        assert_eq!(discord_error(404, 10008), DiscordError::UnknownMessage);
+        assert_eq!(discord_error(400, 50034), DiscordError::TooOldToBulkDelete);
+        assert_eq!(discord_error(400, 50083), DiscordError::ThreadArchived);
+        assert_eq!(discord_error(403, 50013), DiscordError::MissingPermissions);
+        assert_eq!(
+            discord_error(400, 12345),
+            DiscordError::Other {
+                code: 12345,
+                message: "nope".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn statuses_without_a_known_code_are_sorted_out() {
+        assert_eq!(discord_error(429, 0), DiscordError::RateLimited);
+        assert!(matches!(
+            discord_error(502, 0),
+            DiscordError::Unavailable(_)
+        ));
+    }
+
+    #[test]
+    fn each_error_has_a_policy() {
+        assert_eq!(discord_error(429, 0).policy(), ErrorPolicy::Retry);
+        assert_eq!(discord_error(404, 10008).policy(), ErrorPolicy::Skip);
+        assert_eq!(discord_error(400, 50034).policy(), ErrorPolicy::Skip);
+        assert_eq!(discord_error(400, 50083).policy(), ErrorPolicy::Abort);
+        assert!(discord_error(403, 50013).is_misconfiguration());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::models::rules::Decision;
+use chrono::{DateTime, Utc};
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// How many links to affected messages a report includes.
+const SAMPLE_SIZE: usize = 5;
+
+/// What a sweep would have done to a channel and its threads, for checking a retention policy
+/// before it goes live.
+#[derive(Debug, Clone)]
+pub(crate) struct DryRunReport {
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    pub(crate) would_delete: u32,
+    /// Expired messages that were kept, by the rule (or pin) that kept them.
+    pub(crate) protected: BTreeMap<String, u32>,
+    pub(crate) oldest: Option<DateTime<Utc>>,
+    pub(crate) newest: Option<DateTime<Utc>>,
+    samples: Vec<(ChannelId, MessageId)>,
+    pub(crate) errors: u32,
+}
+
+impl DryRunReport {
+    pub(crate) fn new(guild_id: GuildId, channel_id: ChannelId) -> Self {
+        DryRunReport {
+            guild_id,
+            channel_id,
+            would_delete: 0,
+            protected: BTreeMap::new(),
+            oldest: None,
+            newest: None,
+            samples: vec![],
+            errors: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, message: &Message, decision: &Decision) {
+        match decision {
+            Decision::Delete(_) => {
+                let timestamp = *message.timestamp;
+                self.would_delete += 1;
+                self.oldest = Some(self.oldest.map_or(timestamp, |t| t.min(timestamp)));
+                self.newest = Some(self.newest.map_or(timestamp, |t| t.max(timestamp)));
+                if self.samples.len() < SAMPLE_SIZE {
+                    self.samples.push((message.channel_id, message.id));
+                }
+            }
+            Decision::Keep(rule) => *self.protected.entry(rule.clone()).or_default() += 1,
+            Decision::NotExpired(_) => {}
+        }
+    }
+
+    /// The report as a discord message.
+    pub(crate) fn render(&self) -> String {
+        let mut out = format!(
+            "**Dry run of <#{}>**: {} messages would be deleted.",
+            self.channel_id, self.would_delete
+        );
+        if let (Some(oldest), Some(newest)) = (self.oldest, self.newest) {
+            let _ = write!(
+                out,
+                "\nOldest <t:{}:f>, newest <t:{}:f>.",
+                oldest.timestamp(),
+                newest.timestamp()
+            );
+        }
+        if !self.protected.is_empty() {
+            let kept: Vec<String> = self
+                .protected
+                .iter()
+                .map(|(rule, count)| format!("{rule}: {count}"))
+                .collect();
+            let _ = write!(out, "\nKept: {}.", kept.join(", "));
+        }
+        if self.errors > 0 {
+            let _ = write!(out, "\n{} errors, see the logs.", self.errors);
+        }
+        for (channel_id, message_id) in &self.samples {
+            let _ = write!(
+                out,
+                "\nhttps://discord.com/channels/{}/{}/{}",
+                self.guild_id, channel_id, message_id
+            );
+        }
+        out
+    }
+}
@@ -1,14 +1,26 @@
+use crate::commands::emoji::upload::{self, do_emoji_upload, EMOJI_UPLOAD_COMMAND};
 use crate::commands::emoji::{do_emoji, do_emoji_autocomplete};
 use crate::commands::exit::do_exit;
 use crate::commands::llama::{do_llama, do_llama_models};
+use crate::commands::purge::{
+    self, do_forget_me, do_purge_button, do_purge_user, FORGET_ME_COMMAND, PURGE_BUTTON_PREFIX,
+    PURGE_USER_COMMAND,
+};
 use crate::commands::stats::do_stats;
 use crate::commands::stonks::do_stonks;
+use crate::commands::sweeper::{self, do_sweeper, SWEEPER_COMMAND};
+use crate::models::manager::ListenersKey;
+use crate::models::notice::NoticeEvent;
+use crate::models::shutdown::Shutdown;
+use crate::models::ttl::TtlEvent;
 use crate::CONNECTED;
 use serenity::async_trait;
 use serenity::framework::standard::macros::{command, group};
 use serenity::framework::standard::CommandResult;
 use serenity::model::application::interaction::Interaction;
-use serenity::model::application::interaction::Interaction::{ApplicationCommand, Autocomplete};
+use serenity::model::application::interaction::Interaction::{
+    ApplicationCommand, Autocomplete, MessageComponent,
+};
 use serenity::model::channel::Message;
 use serenity::model::gateway::Ready;
 use serenity::model::id::{ChannelId, GuildId};
@@ -17,7 +29,6 @@ use serenity::model::prelude::command::CommandOptionType;
 use serenity::prelude::*;
 use serenity::utils::MessageBuilder;
 use std::env;
-use tokio::sync::mpsc;
 
 const STATS_COMMAND: &str = "stats";
 const STATS_DESCRIPTION: &str = "show stats about the bot";
@@ -63,7 +74,15 @@ impl EventHandler for Handler {
             .set_application_commands(&ctx.http, |builder| {
                 builder
                     .create_application_command(|command| {
-                        command.name(STATS_COMMAND).description(STATS_DESCRIPTION)
+                        command
+                            .name(STATS_COMMAND)
+                            .description(STATS_DESCRIPTION)
+                            .create_option(|option| {
+                                option
+                                    .name("channel")
+                                    .kind(CommandOptionType::Channel)
+                                    .description("Show one swept channel, with its recent runs")
+                            })
                     })
                     .create_application_command(|command| {
                         command
@@ -78,7 +97,24 @@ impl EventHandler for Handler {
                                     .description("Name of emoji to import")
                                     .set_autocomplete(true)
                             })
+                            .create_option(|option| {
+                                option
+                                    .name("variant")
+                                    .kind(CommandOptionType::String)
+                                    .description("Which of the emoji's images to import")
+                                    .set_autocomplete(true)
+                            })
+                            .create_option(|option| {
+                                option
+                                    .name("name")
+                                    .kind(CommandOptionType::String)
+                                    .description("Name to give the emoji in this server")
+                            })
                     })
+                    .create_application_command(upload::register)
+                    .create_application_command(sweeper::register)
+                    .create_application_command(purge::register_forget_me)
+                    .create_application_command(purge::register_purge_user)
             })
             .await
             .expect("failed to create app commands");
@@ -88,16 +124,46 @@ impl EventHandler for Handler {
             .set(true)
             .expect("Couldn't set CONNECTED, nothing will work after this message so paniking");
     }
+    async fn message(&self, ctx: Context, message: Message) {
+        // the listeners ignore channels they aren't following.
+        if let Some(listeners) = ctx.data.read().await.get::<ListenersKey>() {
+            let _ = listeners.ttl.send(TtlEvent::Message {
+                channel_id: message.channel_id,
+                message_id: message.id,
+                author_id: message.author.id,
+                timestamp: *message.timestamp,
+            });
+            if message.author.id != ctx.cache.current_user_id() {
+                let _ = listeners
+                    .notices
+                    .send(NoticeEvent::Message(message.channel_id));
+            }
+        }
+    }
+
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        // shutdown waits for commands that are already running, and new ones are dropped.
+        let Some(_in_flight) = Shutdown::get().command() else {
+            return;
+        };
         if let ApplicationCommand(command) = interaction.clone() {
             match command.data.name.as_str() {
                 STATS_COMMAND => do_stats(&ctx, command).await,
                 EMOJI_COMMAND => do_emoji(&ctx, command).await,
+                EMOJI_UPLOAD_COMMAND => do_emoji_upload(&ctx, command).await,
+                SWEEPER_COMMAND => do_sweeper(&ctx, command).await,
+                FORGET_ME_COMMAND => do_forget_me(&ctx, command).await,
+                PURGE_USER_COMMAND => do_purge_user(&ctx, command).await,
                 _ => {
                     return;
                 }
             }
         };
+        if let MessageComponent(component) = interaction.clone() {
+            if component.data.custom_id.starts_with(PURGE_BUTTON_PREFIX) {
+                do_purge_button(&ctx, component).await;
+            }
+        };
         if let Autocomplete(command) = interaction {
             match command.data.name.as_str() {
                 EMOJI_COMMAND => do_emoji_autocomplete(&ctx, command).await,
@@ -129,6 +195,11 @@ async fn llama_models(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 async fn llama(ctx: &Context, msg: &Message) -> CommandResult {
     info!("Received llama request.");
+    let Some(_in_flight) = Shutdown::get().command() else {
+        msg.reply(ctx, "Shutting down, try again in a minute.")
+            .await?;
+        return Ok(());
+    };
     do_llama(ctx, msg).await
 }
 
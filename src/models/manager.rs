@@ -0,0 +1,426 @@
+use crate::models::config::{default_max_message_age, to_chrono, ChannelConfig, Config};
+use crate::models::db::Database;
+use crate::models::notice::NoticeEvent;
+use crate::models::scheduler::Schedule;
+use crate::models::shutdown::Shutdown;
+use crate::models::store::MessageStore;
+use crate::models::sweeper::{run_sweeper, Stats, Sweeper};
+use crate::models::ttl::TtlEvent;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use duration_string::DurationString;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity::prelude::TypeMapKey;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Owns the sweepers, when each is next due, and the config they were built from.  The scheduler
+/// asks it which sweepers to run; every change made through it is written back to the config
+/// file.
+pub(crate) struct SweeperManager {
+    store: Arc<dyn MessageStore>,
+    guild_id: GuildId,
+    config_path: PathBuf,
+    config: Config,
+    dry_run: bool,
+    db: Arc<Database>,
+    running: HashMap<ChannelId, ScheduledSweeper>,
+    wake: Arc<Notify>,
+    listeners: Listeners,
+    /// Channels in TTL mode are handed to the TTL reaper instead of the scheduler.
+    ttl_channels: HashSet<ChannelId>,
+}
+
+struct ScheduledSweeper {
+    sweeper: Arc<Mutex<Sweeper>>,
+    schedule: Schedule,
+    next_run: DateTime<Utc>,
+    /// The sweep in progress, if there is one.
+    task: Option<JoinHandle<()>>,
+    /// Stops the sweep in progress once its current chunk is done.
+    stop: CancellationToken,
+    /// When to post a heads-up before each sweep, and what it says.
+    heads_up: Option<(Duration, String)>,
+    warned: bool,
+}
+
+impl ScheduledSweeper {
+    fn heads_up_at(&self) -> Option<DateTime<Utc>> {
+        match (&self.heads_up, self.warned) {
+            (Some((warn_before, _)), false) => Some(self.next_run - *warn_before),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct SweeperManagerKey;
+
+impl TypeMapKey for SweeperManagerKey {
+    type Value = Arc<Mutex<SweeperManager>>;
+}
+
+/// The background tasks that follow what happens in swept channels.  The manager tells them which
+/// channels to follow; the handler passes on the messages it sees.
+#[derive(Clone)]
+pub(crate) struct Listeners {
+    pub(crate) ttl: mpsc::UnboundedSender<TtlEvent>,
+    pub(crate) notices: mpsc::UnboundedSender<NoticeEvent>,
+}
+
+pub(crate) struct ListenersKey;
+
+impl TypeMapKey for ListenersKey {
+    type Value = Listeners;
+}
+
+impl SweeperManager {
+    pub(crate) fn new(
+        store: Arc<dyn MessageStore>,
+        guild_id: GuildId,
+        config_path: PathBuf,
+        config: Config,
+        dry_run: bool,
+        db: Arc<Database>,
+        listeners: Listeners,
+    ) -> Self {
+        SweeperManager {
+            store,
+            guild_id,
+            config_path,
+            config,
+            dry_run,
+            db,
+            running: HashMap::new(),
+            wake: Arc::new(Notify::new()),
+            listeners,
+            ttl_channels: HashSet::new(),
+        }
+    }
+
+    /// The handle the scheduler waits on, so changes made here take effect straight away.
+    pub(crate) fn waker(&self) -> Arc<Notify> {
+        self.wake.clone()
+    }
+
+    pub(crate) fn channels(&self) -> &[ChannelConfig] {
+        &self.config.channels
+    }
+
+    pub(crate) fn is_running(&self, channel_id: ChannelId) -> bool {
+        self.running.contains_key(&channel_id) || self.ttl_channels.contains(&channel_id)
+    }
+
+    pub(crate) fn next_run(&self, channel_id: ChannelId) -> Option<DateTime<Utc>> {
+        self.running.get(&channel_id).map(|s| s.next_run)
+    }
+
+    /// Start a sweep for every channel that is due at `now`, post heads-ups for the ones about to
+    /// be, and work out when they're next due.  Returns the earliest time the scheduler needs to
+    /// wake up.  A channel whose previous sweep is still going skips this slot.
+    pub(crate) fn start_due(&mut self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        for (channel_id, scheduled) in self.running.iter_mut() {
+            if let (Some(heads_up_at), Some((_, text))) =
+                (scheduled.heads_up_at(), &scheduled.heads_up)
+            {
+                if heads_up_at <= now && scheduled.next_run > now {
+                    let _ = self.listeners.notices.send(NoticeEvent::HeadsUp {
+                        channel_id: *channel_id,
+                        text: text.clone(),
+                        sweep_at: scheduled.next_run,
+                    });
+                    scheduled.warned = true;
+                }
+            }
+            if scheduled.next_run > now {
+                continue;
+            }
+            scheduled.next_run = scheduled.schedule.next_after(now);
+            scheduled.warned = false;
+            if scheduled.task.as_ref().is_some_and(|t| !t.is_finished()) {
+                warn!(
+                    "Sweep of {} is still running, skipping this slot.",
+                    channel_id
+                );
+                continue;
+            }
+            let sweeper = scheduled.sweeper.clone();
+            scheduled.task = Some(Shutdown::get().spawn(async move {
+                run_sweeper(&mut *sweeper.lock().await).await;
+            }));
+            info!(
+                "Started sweep of {}, next due at {}",
+                channel_id, scheduled.next_run
+            );
+        }
+        self.running
+            .values()
+            .flat_map(|s| [Some(s.next_run), s.heads_up_at()])
+            .flatten()
+            .min()
+    }
+
+    /// Start a sweeper for every channel that isn't paused.
+    pub(crate) fn start_all(&mut self) -> Vec<watch::Receiver<Stats>> {
+        let channels: Vec<ChannelId> = self
+            .config
+            .channels
+            .iter()
+            .filter(|c| !c.paused)
+            .map(|c| c.channel_id())
+            .collect();
+        channels
+            .into_iter()
+            .filter_map(|channel_id| self.start(channel_id))
+            .collect()
+    }
+
+    pub(crate) fn add(&mut self, channel: ChannelConfig) -> Result<watch::Receiver<Stats>> {
+        let channel_id = channel.channel_id();
+        self.update(|config| {
+            if config.channel(channel_id).is_some() {
+                bail!("channel {} is already swept", channel_id);
+            }
+            config.channels.push(channel);
+            Ok(())
+        })?;
+        match self.start(channel_id) {
+            Some(rx) => Ok(rx),
+            None => bail!(
+                "channel {} was added but its sweeper didn't start",
+                channel_id
+            ),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, channel_id: ChannelId) -> Result<()> {
+        self.update(|config| {
+            if config.channel(channel_id).is_none() {
+                bail!("channel {} isn't swept", channel_id);
+            }
+            config.channels.retain(|c| c.id != channel_id.0);
+            Ok(())
+        })?;
+        self.stop(channel_id);
+        Ok(())
+    }
+
+    pub(crate) fn pause(&mut self, channel_id: ChannelId) -> Result<()> {
+        self.update(|config| match config.channel_mut(channel_id) {
+            Some(channel) if channel.paused => bail!("channel {} is already paused", channel_id),
+            Some(channel) => {
+                channel.paused = true;
+                Ok(())
+            }
+            None => bail!("channel {} isn't swept", channel_id),
+        })?;
+        self.stop(channel_id);
+        Ok(())
+    }
+
+    pub(crate) fn resume(&mut self, channel_id: ChannelId) -> Result<watch::Receiver<Stats>> {
+        self.update(|config| match config.channel_mut(channel_id) {
+            Some(channel) if !channel.paused => bail!("channel {} isn't paused", channel_id),
+            Some(channel) => {
+                channel.paused = false;
+                Ok(())
+            }
+            None => bail!("channel {} isn't swept", channel_id),
+        })?;
+        match self.start(channel_id) {
+            Some(rx) => Ok(rx),
+            None => bail!(
+                "channel {} was resumed but its sweeper didn't start",
+                channel_id
+            ),
+        }
+    }
+
+    /// Change a channel's max message age.  A running sweeper is restarted so it picks up the new
+    /// age, in which case the new stats receiver is returned.
+    pub(crate) fn set_age(
+        &mut self,
+        channel_id: ChannelId,
+        max_message_age: DurationString,
+    ) -> Result<Option<watch::Receiver<Stats>>> {
+        self.update(|config| match config.channel_mut(channel_id) {
+            Some(channel) => {
+                channel.max_message_age = max_message_age;
+                Ok(())
+            }
+            None => bail!("channel {} isn't swept", channel_id),
+        })?;
+        if self.stop(channel_id) {
+            return Ok(self.start(channel_id));
+        }
+        Ok(None)
+    }
+
+    /// A dry run sweeper for previewing a channel, with its current settings or, if it isn't swept
+    /// yet, the defaults.  `max_message_age` tries out a different age.
+    pub(crate) fn preview_sweeper(
+        &self,
+        channel_id: ChannelId,
+        max_message_age: Option<DurationString>,
+    ) -> Sweeper {
+        let mut channel = self
+            .config
+            .channel(channel_id)
+            .cloned()
+            .unwrap_or_else(|| ChannelConfig::new(channel_id, default_max_message_age(), true));
+        // a TTL channel's messages expire after its ttl.
+        if let Some(max_message_age) = max_message_age.or(channel.ttl) {
+            channel.max_message_age = max_message_age;
+        }
+        let (sweeper, _stats) = Sweeper::new(
+            self.store.clone(),
+            self.guild_id,
+            &channel,
+            true,
+            self.db.clone(),
+        );
+        sweeper
+    }
+
+    /// Sweepers for purging a member's messages from every configured channel, threads and all.
+    /// A channel's own dry run setting doesn't apply, and the member's messages too old for bulk
+    /// delete are all deleted in one go.
+    pub(crate) fn purge_sweepers(&self) -> Vec<Sweeper> {
+        self.config
+            .channels
+            .iter()
+            .map(|channel| {
+                let mut channel = channel.clone();
+                channel.dry_run = false;
+                channel.threads.archived = true;
+                Sweeper::purger(
+                    self.store.clone(),
+                    self.guild_id,
+                    &channel,
+                    self.dry_run,
+                    self.db.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// Make a channel due straight away instead of waiting for its next scheduled run.
+    pub(crate) fn run_now(&mut self, channel_id: ChannelId) -> Result<()> {
+        if self.ttl_channels.contains(&channel_id) {
+            bail!(
+                "channel {} uses a ttl, its messages are deleted as they expire",
+                channel_id
+            );
+        }
+        match self.running.get_mut(&channel_id) {
+            Some(scheduled) => {
+                scheduled.next_run = Utc::now();
+                self.wake.notify_one();
+                Ok(())
+            }
+            None => bail!("channel {} has no running sweeper", channel_id),
+        }
+    }
+
+    /// Hand a channel's sweeper to the scheduler.  It's due straight away.  A TTL channel goes to
+    /// the TTL reaper instead.
+    fn start(&mut self, channel_id: ChannelId) -> Option<watch::Receiver<Stats>> {
+        let channel = self.config.channel(channel_id)?;
+        if let (Some(text), Some(notice)) = (channel.notice_text(), &channel.notice) {
+            let _ = self.listeners.notices.send(NoticeEvent::Watch {
+                channel_id,
+                text,
+                repost_after: notice.repost_after,
+            });
+        }
+        if let Some(ttl) = channel.ttl {
+            let (stats_tx, stats) = watch::channel(Stats::new(channel_id));
+            let watch = TtlEvent::Watch {
+                channel: Box::new(channel.clone()),
+                dry_run: self.dry_run,
+                stats_tx,
+            };
+            if self.listeners.ttl.send(watch).is_err() {
+                error!("TTL reaper isn't running, can't watch {}", channel_id);
+                return None;
+            }
+            info!("Deleting messages in {} after {}", channel_id, ttl);
+            self.ttl_channels.insert(channel_id);
+            return Some(stats);
+        }
+        let (sweeper, stats) = Sweeper::new(
+            self.store.clone(),
+            self.guild_id,
+            channel,
+            self.dry_run,
+            self.db.clone(),
+        );
+        info!(
+            "Scheduled sweeper for {}: {}",
+            channel_id,
+            channel.schedule.describe()
+        );
+        let heads_up = channel
+            .notice
+            .as_ref()
+            .and_then(|n| n.warn_before)
+            .map(|warn_before| {
+                let text = format!(
+                    "Heads up: messages older than {} are about to be deleted.",
+                    channel.max_message_age
+                );
+                (to_chrono(warn_before), text)
+            });
+        self.running.insert(
+            channel_id,
+            ScheduledSweeper {
+                stop: sweeper.stop_token(),
+                sweeper: Arc::new(Mutex::new(sweeper)),
+                schedule: channel.schedule.clone(),
+                next_run: Utc::now(),
+                task: None,
+                heads_up,
+                warned: false,
+            },
+        );
+        self.wake.notify_one();
+        Some(stats)
+    }
+
+    /// Take a channel's sweeper away from the scheduler.  A sweep in progress stops after the chunk
+    /// it's on, so nothing is archived without being deleted or deleted without being audited.
+    /// Returns whether it was scheduled.
+    fn stop(&mut self, channel_id: ChannelId) -> bool {
+        let _ = self
+            .listeners
+            .notices
+            .send(NoticeEvent::Unwatch(channel_id));
+        if self.ttl_channels.remove(&channel_id) {
+            let _ = self.listeners.ttl.send(TtlEvent::Unwatch(channel_id));
+            info!("Stopped TTL deletes for {}", channel_id);
+            return true;
+        }
+        match self.running.remove(&channel_id) {
+            Some(scheduled) => {
+                scheduled.stop.cancel();
+                info!("Stopped sweeper for {}", channel_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Apply a change to a copy of the config and only keep it once it has been validated and
+    /// written to disk, so the running sweepers never drift from what a restart would load.
+    fn update(&mut self, change: impl FnOnce(&mut Config) -> Result<()>) -> Result<()> {
+        let mut config = self.config.clone();
+        change(&mut config)?;
+        config.validate()?;
+        config.save(&self.config_path)?;
+        self.config = config;
+        Ok(())
+    }
+}
@@ -1,2 +1,16 @@
+pub(crate) mod archive;
+pub(crate) mod budget;
+pub(crate) mod config;
+pub(crate) mod db;
+pub(crate) mod digest;
+pub(crate) mod discord_error;
+pub(crate) mod dry_run;
 pub(crate) mod handler;
+pub(crate) mod manager;
+pub(crate) mod notice;
+pub(crate) mod rules;
+pub(crate) mod scheduler;
+pub(crate) mod shutdown;
+pub(crate) mod store;
 pub(crate) mod sweeper;
+pub(crate) mod ttl;
@@ -0,0 +1,255 @@
+use crate::models::db::Database;
+use crate::models::discord_error::DiscordError;
+use crate::models::shutdown::Shutdown;
+use crate::models::store::MessageStore;
+use crate::CONNECTED;
+use chrono::{DateTime, Utc};
+use serenity::model::id::{ChannelId, MessageId};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Protected message kinds the notice board owns.
+const NOTICE_KIND: &str = "notice";
+const HEADS_UP_KIND: &str = "heads_up";
+
+/// What the notice board is told about.  The handler sends every message it sees that isn't the
+/// bot's own; the manager sends channels as they start and stop, and when a sweep is coming up.
+pub(crate) enum NoticeEvent {
+    Watch {
+        channel_id: ChannelId,
+        text: String,
+        repost_after: u32,
+    },
+    Unwatch(ChannelId),
+    Message(ChannelId),
+    HeadsUp {
+        channel_id: ChannelId,
+        text: String,
+        sweep_at: DateTime<Utc>,
+    },
+}
+
+/// Keeps a notice at the bottom of each swept channel that asks for one, saying how long messages
+/// last there.  The notice is reposted once enough messages have been sent after it to scroll it
+/// away.  The notices (and heads-ups before a sweep) are recorded as protected messages, so no
+/// sweep deletes them.
+pub(crate) struct NoticeBoard {
+    store: Arc<dyn MessageStore>,
+    db: Arc<Database>,
+    rx: mpsc::UnboundedReceiver<NoticeEvent>,
+    channels: HashMap<ChannelId, NoticeChannel>,
+}
+
+struct NoticeChannel {
+    text: String,
+    repost_after: u32,
+    /// Messages sent since the notice was posted.
+    since: u32,
+}
+
+impl NoticeBoard {
+    pub(crate) fn new(
+        store: Arc<dyn MessageStore>,
+        db: Arc<Database>,
+    ) -> (Self, mpsc::UnboundedSender<NoticeEvent>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            NoticeBoard {
+                store,
+                db,
+                rx,
+                channels: HashMap::new(),
+            },
+            tx,
+        )
+    }
+
+    pub(crate) async fn run(mut self) {
+        while !CONNECTED.initialized() {
+            tokio::time::sleep(core::time::Duration::from_secs(1)).await;
+        }
+
+        info!("Bot ready, starting notice board.");
+        loop {
+            tokio::select! {
+                event = self.rx.recv() => match event {
+                    Some(event) => self.handle(event).await,
+                    None => return,
+                },
+                _ = Shutdown::get().requested() => {
+                    info!("Shutting down, stopping notice board.");
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, event: NoticeEvent) {
+        match event {
+            NoticeEvent::Watch {
+                channel_id,
+                text,
+                repost_after,
+            } => {
+                self.channels.insert(
+                    channel_id,
+                    NoticeChannel {
+                        text,
+                        repost_after,
+                        since: 0,
+                    },
+                );
+                // the text may have changed while the bot was down, so start fresh.
+                self.repost(channel_id).await;
+            }
+            NoticeEvent::Unwatch(channel_id) => {
+                if self.channels.remove(&channel_id).is_some() {
+                    self.take_down(channel_id, NOTICE_KIND).await;
+                    self.take_down(channel_id, HEADS_UP_KIND).await;
+                }
+            }
+            NoticeEvent::Message(channel_id) => {
+                let Some(channel) = self.channels.get_mut(&channel_id) else {
+                    return;
+                };
+                channel.since += 1;
+                if channel.since >= channel.repost_after {
+                    self.repost(channel_id).await;
+                }
+            }
+            NoticeEvent::HeadsUp {
+                channel_id,
+                text,
+                sweep_at,
+            } => {
+                self.take_down(channel_id, HEADS_UP_KIND).await;
+                let text = format!("{text} Next sweep <t:{}:R>.", sweep_at.timestamp());
+                self.post(channel_id, text, HEADS_UP_KIND).await;
+            }
+        }
+    }
+
+    /// Post the channel's notice again at the bottom and take the old one down.
+    async fn repost(&mut self, channel_id: ChannelId) {
+        let Some(channel) = self.channels.get_mut(&channel_id) else {
+            return;
+        };
+        channel.since = 0;
+        let text = channel.text.clone();
+        self.take_down(channel_id, NOTICE_KIND).await;
+        self.post(channel_id, text, NOTICE_KIND).await;
+    }
+
+    async fn post(&self, channel_id: ChannelId, text: String, kind: &str) {
+        match self.store.say(channel_id, text).await {
+            Ok(message_id) => {
+                if let Err(e) = self.db.protect(channel_id, message_id, kind) {
+                    error!("Couldn't protect {kind} in {}: {e:#}", channel_id);
+                }
+            }
+            Err(e) if e.is_misconfiguration() => error!(
+                "Couldn't post {kind} in {}: {e}. Give the bot Send Messages there.",
+                channel_id
+            ),
+            Err(e) => error!("Couldn't post {kind} in {}: {e}", channel_id),
+        }
+    }
+
+    /// Delete the channel's protected messages of one kind.
+    async fn take_down(&self, channel_id: ChannelId, kind: &str) {
+        let messages: Vec<MessageId> = match self.db.protected_messages(channel_id) {
+            Ok(messages) => messages
+                .into_iter()
+                .filter(|(_, k)| k == kind)
+                .map(|(id, _)| id)
+                .collect(),
+            Err(e) => {
+                error!("Couldn't look up the {kind} in {}: {e:#}", channel_id);
+                return;
+            }
+        };
+        for message_id in messages {
+            match self.store.delete_message(channel_id, message_id).await {
+                // it's already gone, so there's nothing left to protect.
+                Ok(()) | Err(DiscordError::UnknownMessage) => {}
+                Err(e) => warn!(%message_id, "Couldn't delete old {kind}: {e}"),
+            }
+            if let Err(e) = self.db.unprotect(message_id) {
+                error!("Couldn't unprotect old {kind}: {e:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::store::fake::FakeStore;
+
+    const CHANNEL: ChannelId = ChannelId(10);
+
+    fn board() -> (NoticeBoard, Arc<FakeStore>, Arc<Database>) {
+        let store = Arc::new(FakeStore::default());
+        let db = Arc::new(Database::open(":memory:").unwrap());
+        let (board, _) = NoticeBoard::new(store.clone(), db.clone());
+        (board, store, db)
+    }
+
+    fn watch(repost_after: u32) -> NoticeEvent {
+        NoticeEvent::Watch {
+            channel_id: CHANNEL,
+            text: "Messages here last a day.".to_string(),
+            repost_after,
+        }
+    }
+
+    fn protected(db: &Database) -> Vec<(MessageId, String)> {
+        db.protected_messages(CHANNEL).unwrap()
+    }
+
+    #[tokio::test]
+    async fn notices_are_posted_through_the_store_and_protected() {
+        let (mut board, store, db) = board();
+        board.handle(watch(3)).await;
+        assert_eq!(
+            store.state().said,
+            [(CHANNEL, "Messages here last a day.".to_string())]
+        );
+        assert_eq!(protected(&db), [(MessageId(1), NOTICE_KIND.to_string())]);
+    }
+
+    #[tokio::test]
+    async fn notices_are_reposted_once_they_scroll_away() {
+        let (mut board, store, db) = board();
+        board.handle(watch(3)).await;
+        board.handle(NoticeEvent::Message(CHANNEL)).await;
+        board.handle(NoticeEvent::Message(CHANNEL)).await;
+        assert_eq!(store.state().said.len(), 1);
+
+        board.handle(NoticeEvent::Message(CHANNEL)).await;
+        assert_eq!(store.state().said.len(), 2);
+        assert_eq!(protected(&db), [(MessageId(2), NOTICE_KIND.to_string())]);
+    }
+
+    #[tokio::test]
+    async fn unwatching_takes_the_notices_down() {
+        let (mut board, store, db) = board();
+        board.handle(watch(3)).await;
+        board
+            .handle(NoticeEvent::HeadsUp {
+                channel_id: CHANNEL,
+                text: "Sweeping soon.".to_string(),
+                sweep_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            })
+            .await;
+        assert_eq!(
+            store.state().said[1].1,
+            "Sweeping soon. Next sweep <t:1700000000:R>."
+        );
+        assert_eq!(protected(&db).len(), 2);
+
+        board.handle(NoticeEvent::Unwatch(CHANNEL)).await;
+        assert!(protected(&db).is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+use crate::models::config::to_chrono;
+use crate::models::discord_error::DiscordError;
+use crate::models::store::MessageStore;
+use chrono::{DateTime, Duration, Utc};
+use duration_string::DurationString;
+use serde::{Deserialize, Serialize};
+use serenity::model::channel::{Message, ReactionType};
+use serenity::model::id::{ChannelId, GuildId, MessageId, RoleId, UserId};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A retention rule for a swept channel, configured as a `[[channel.rules]]` table with a `kind`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Rule {
+    /// Keep messages with at least `count` reactions, across all emoji.
+    MinReactions { count: u64 },
+    /// Keep messages that carry this reaction.  Either a unicode emoji or a custom emoji's name.
+    Reaction { emoji: String },
+    /// Keep messages from members holding any of these roles.
+    Roles { ids: Vec<u64> },
+    /// Keep messages from these users.
+    Users { ids: Vec<u64> },
+    /// Keep messages with attachments.
+    Attachments,
+    /// Messages from bots expire after `max_age` instead of the channel's max age.
+    BotMaxAge { max_age: DurationString },
+    /// Keep the newest `count` messages in the channel, however old they are.
+    KeepLast { count: usize },
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::MinReactions { count } => write!(f, "min_reactions({count})"),
+            Rule::Reaction { emoji } => write!(f, "reaction({emoji})"),
+            Rule::Roles { .. } => write!(f, "roles"),
+            Rule::Users { .. } => write!(f, "users"),
+            Rule::Attachments => write!(f, "attachments"),
+            Rule::BotMaxAge { max_age } => write!(f, "bot_max_age({max_age})"),
+            Rule::KeepLast { count } => write!(f, "keep_last({count})"),
+        }
+    }
+}
+
+/// Lets members keep a message from the sweeper by reacting to it, since they can't pin.  Configured
+/// as a channel's `[channel.keep]` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct KeepReaction {
+    /// A unicode emoji or a custom emoji's name.
+    pub(crate) emoji: String,
+    /// How many members have to react before the message is kept.
+    #[serde(default = "default_keep_count")]
+    pub(crate) min_count: u64,
+    /// Only reactions from members holding this role count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) role: Option<u64>,
+    /// How long past its normal expiry a kept message is kept for.  Forever when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) extension: Option<DurationString>,
+}
+
+fn default_keep_count() -> u64 {
+    1
+}
+
+impl fmt::Display for KeepReaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "keep({})", self.emoji)
+    }
+}
+
+/// What the rule engine decided to do with a message, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Decision {
+    Delete(String),
+    /// The message has expired but a rule (or its pin) keeps it.
+    Keep(String),
+    NotExpired(String),
+}
+
+/// The rules for one sweep of a channel, along with whatever they need looked up from discord.
+pub(crate) struct RuleSet {
+    rules: Vec<Rule>,
+    keep: Option<KeepReaction>,
+    store: Arc<dyn MessageStore>,
+    guild_id: GuildId,
+    now: DateTime<Utc>,
+    max_message_age: Duration,
+    bot_max_age: Option<Duration>,
+    newest: HashSet<MessageId>,
+    /// The bot's own notices, which stay until the bot takes them down.
+    protected: HashSet<MessageId>,
+    roles: Mutex<HashMap<UserId, Vec<RoleId>>>,
+}
+
+impl RuleSet {
+    /// Build the rule set for a sweep starting at `now`.  Rules that need to know about the whole
+    /// channel (`keep_last`) look it up here.
+    pub(crate) async fn prepare(
+        rules: &[Rule],
+        keep: Option<&KeepReaction>,
+        store: Arc<dyn MessageStore>,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        now: DateTime<Utc>,
+        max_message_age: Duration,
+    ) -> Result<RuleSet, DiscordError> {
+        let bot_max_age = rules.iter().find_map(|r| match r {
+            Rule::BotMaxAge { max_age } => Some(to_chrono(*max_age)),
+            _ => None,
+        });
+        let keep_last = rules
+            .iter()
+            .filter_map(|r| match r {
+                Rule::KeepLast { count } => Some(*count),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        let mut newest = HashSet::new();
+        let mut cursor: Option<MessageId> = None;
+        while newest.len() < keep_last {
+            let limit = (keep_last - newest.len()).min(100) as u64;
+            let page = store.messages(channel_id, cursor, limit).await?;
+            if page.is_empty() {
+                break;
+            }
+            cursor = page.iter().map(|m| m.id).min();
+            newest.extend(page.iter().map(|m| m.id));
+        }
+
+        Ok(RuleSet {
+            rules: rules.to_vec(),
+            keep: keep.cloned(),
+            store,
+            guild_id,
+            now,
+            max_message_age,
+            bot_max_age,
+            newest,
+            protected: HashSet::new(),
+            roles: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Exempt these messages from the sweep.
+    pub(crate) fn protect(mut self, messages: impl IntoIterator<Item = MessageId>) -> Self {
+        self.protected.extend(messages);
+        self
+    }
+
+    /// The sweep has to read messages up to the latest cutoff any message could have.
+    pub(crate) fn stream_cutoff(&self) -> DateTime<Utc> {
+        let cutoff = self.now - self.max_message_age;
+        match self.bot_max_age {
+            Some(bot_max_age) => cutoff.max(self.now - bot_max_age),
+            None => cutoff,
+        }
+    }
+
+    /// Decide whether a message should go.  Exemptions are checked before expiry so the log says
+    /// which rule saved a message.
+    pub(crate) async fn evaluate(&self, message: &Message) -> Decision {
+        if message.pinned {
+            return Decision::Keep("pinned".to_string());
+        }
+        if self.protected.contains(&message.id) {
+            return Decision::Keep("bot notice".to_string());
+        }
+
+        let (cutoff, expiry) = match (message.author.bot, self.bot_max_age) {
+            (true, Some(bot_max_age)) => (
+                self.now - bot_max_age,
+                format!("bot_max_age({})", humanize(bot_max_age)),
+            ),
+            _ => (
+                self.now - self.max_message_age,
+                format!("max_message_age({})", humanize(self.max_message_age)),
+            ),
+        };
+        if message.timestamp.deref() >= &cutoff {
+            return Decision::NotExpired(format!("not expired under {expiry}"));
+        }
+
+        if let Some(keep) = &self.keep {
+            let extended = match keep.extension {
+                Some(extension) => message.timestamp.deref() >= &(cutoff - to_chrono(extension)),
+                None => true,
+            };
+            if extended && self.kept_by_reaction(keep, message).await {
+                return Decision::Keep(keep.to_string());
+            }
+        }
+
+        for rule in &self.rules {
+            if self.keeps(rule, message).await {
+                return Decision::Keep(rule.to_string());
+            }
+        }
+
+        Decision::Delete(expiry)
+    }
+
+    async fn keeps(&self, rule: &Rule, message: &Message) -> bool {
+        match rule {
+            Rule::MinReactions { count } => {
+                message.reactions.iter().map(|r| r.count).sum::<u64>() >= *count
+            }
+            Rule::Reaction { emoji } => message
+                .reactions
+                .iter()
+                .any(|r| reaction_matches(&r.reaction_type, emoji)),
+            Rule::Roles { ids } => match self.member_roles(message.author.id).await {
+                Ok(roles) => roles.iter().any(|role| ids.contains(&role.0)),
+                Err(e) => {
+                    warn!(%message.id, "Couldn't look up the author's roles, keeping the message: {e}");
+                    true
+                }
+            },
+            Rule::Users { ids } => ids.contains(&message.author.id.0),
+            Rule::Attachments => !message.attachments.is_empty(),
+            Rule::BotMaxAge { .. } => false,
+            Rule::KeepLast { .. } => self.newest.contains(&message.id),
+        }
+    }
+
+    /// Whether enough members reacted with the keep emoji.  With a role set, the reactors are
+    /// looked up so only members holding it count.
+    async fn kept_by_reaction(&self, keep: &KeepReaction, message: &Message) -> bool {
+        let Some(reaction) = message
+            .reactions
+            .iter()
+            .find(|r| reaction_matches(&r.reaction_type, &keep.emoji))
+        else {
+            return false;
+        };
+        if reaction.count < keep.min_count {
+            return false;
+        }
+        let Some(role) = keep.role else {
+            return true;
+        };
+
+        let reactors = match self
+            .store
+            .reaction_users(
+                message.channel_id,
+                message.id,
+                reaction.reaction_type.clone(),
+            )
+            .await
+        {
+            Ok(users) => users,
+            Err(e) => {
+                // keeping a message by mistake is cheaper than deleting one.
+                warn!(%message.id, "Couldn't look up who reacted, keeping the message: {e}");
+                return true;
+            }
+        };
+        let mut count = 0;
+        for user in reactors {
+            match self.member_roles(user.id).await {
+                Ok(roles) if roles.contains(&RoleId(role)) => count += 1,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(%message.id, "Couldn't look up a reactor's roles, keeping the message: {e}");
+                    return true;
+                }
+            }
+        }
+        count >= keep.min_count
+    }
+
+    /// Messages fetched over REST don't carry the author's roles, so look the member up once per
+    /// sweep.  Someone who has left the guild has no roles.  Any other failure isn't cached, so the
+    /// next message tries again.
+    async fn member_roles(&self, user_id: UserId) -> Result<Vec<RoleId>, DiscordError> {
+        let mut roles = self.roles.lock().await;
+        if let Some(cached) = roles.get(&user_id) {
+            return Ok(cached.clone());
+        }
+        let member_roles = match self.store.member_roles(self.guild_id, user_id).await {
+            Ok(member_roles) => member_roles,
+            Err(DiscordError::UnknownMember) => vec![],
+            Err(e) => return Err(e),
+        };
+        roles.insert(user_id, member_roles.clone());
+        Ok(member_roles)
+    }
+}
+
+pub(crate) fn reaction_matches(reaction: &ReactionType, emoji: &str) -> bool {
+    match reaction {
+        ReactionType::Unicode(unicode) => unicode == emoji,
+        ReactionType::Custom { name, .. } => {
+            name.as_deref() == Some(emoji.trim_matches(':')) || reaction.to_string() == emoji
+        }
+        _ => false,
+    }
+}
+
+fn humanize(duration: Duration) -> DurationString {
+    DurationString::new(duration.to_std().unwrap_or_default())
+}
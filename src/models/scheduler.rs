@@ -0,0 +1,281 @@
+use crate::models::config::to_chrono;
+use crate::models::manager::SweeperManager;
+use crate::models::shutdown::Shutdown;
+use crate::CONNECTED;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use duration_string::DurationString;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
+
+/// When a channel is swept: either `every` some interval or on a `cron` expression
+/// (`sec min hour day-of-month month day-of-week`), evaluated in `timezone`.  Runs that would land
+/// inside the optional `quiet` window are pushed to the end of it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Schedule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) every: Option<DurationString>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cron: Option<String>,
+    /// An IANA timezone name, e.g. `America/New_York`.  Defaults to UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) quiet: Option<QuietWindow>,
+}
+
+/// A daily window, in the schedule's timezone, during which the channel isn't swept.  `start`
+/// and `end` are `HH:MM`; a window can wrap past midnight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QuietWindow {
+    pub(crate) start: String,
+    pub(crate) end: String,
+}
+
+const DEFAULT_INTERVAL_HOURS: i64 = 1;
+
+impl Schedule {
+    pub(crate) fn validate(&self) -> Result<()> {
+        match (&self.every, &self.cron) {
+            (Some(_), Some(_)) => bail!("a schedule can't have both every and cron"),
+            (Some(every), None) if to_chrono(*every) <= Duration::zero() => {
+                bail!("a schedule's every can't be zero")
+            }
+            (None, Some(expr)) => {
+                cron::Schedule::from_str(expr)
+                    .with_context(|| format!("bad cron expression {expr:?}"))?;
+            }
+            _ => {}
+        }
+        self.tz()?;
+        if let Some(quiet) = &self.quiet {
+            quiet.times()?;
+        }
+        Ok(())
+    }
+
+    fn tz(&self) -> Result<Tz> {
+        match &self.timezone {
+            Some(name) => name
+                .parse::<Tz>()
+                .map_err(|e| anyhow::anyhow!("bad timezone {name:?}: {e}")),
+            None => Ok(Tz::UTC),
+        }
+    }
+
+    /// The first time after `after` that the channel should be swept.
+    pub(crate) fn next_after(&self, after: DateTime<Utc>) -> DateTime<Utc> {
+        // the config was validated when it was loaded.
+        let tz = self.tz().unwrap_or(Tz::UTC);
+        let next = match (&self.cron, self.every) {
+            (Some(expr), _) => cron::Schedule::from_str(expr)
+                .ok()
+                .and_then(|s| s.after(&after.with_timezone(&tz)).next())
+                .map(|t| t.with_timezone(&Utc))
+                .unwrap_or_else(|| after + Duration::hours(DEFAULT_INTERVAL_HOURS)),
+            (None, Some(every)) => after + to_chrono(every),
+            (None, None) => after + Duration::hours(DEFAULT_INTERVAL_HOURS),
+        };
+        match &self.quiet {
+            Some(quiet) => quiet.push_out(next, tz),
+            None => next,
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let base = match (&self.cron, self.every) {
+            (Some(expr), _) => format!("cron `{expr}`"),
+            (None, Some(every)) => format!("every {every}"),
+            (None, None) => format!("every {DEFAULT_INTERVAL_HOURS}h"),
+        };
+        let tz = match &self.timezone {
+            Some(tz) => format!(" ({tz})"),
+            None => String::new(),
+        };
+        match &self.quiet {
+            Some(quiet) => format!("{base}{tz}, quiet {}-{}", quiet.start, quiet.end),
+            None => format!("{base}{tz}"),
+        }
+    }
+}
+
+impl QuietWindow {
+    fn times(&self) -> Result<(NaiveTime, NaiveTime)> {
+        let parse = |s: &str| {
+            NaiveTime::parse_from_str(s, "%H:%M")
+                .with_context(|| format!("bad quiet window time {s:?}, expected HH:MM"))
+        };
+        Ok((parse(&self.start)?, parse(&self.end)?))
+    }
+
+    /// If `at` falls inside the window, move it to the window's end.
+    fn push_out(&self, at: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        let Ok((start, end)) = self.times() else {
+            return at;
+        };
+        let local = at.with_timezone(&tz);
+        let time = local.time();
+        let inside = match start <= end {
+            true => time >= start && time < end,
+            // the window wraps past midnight, e.g. 22:00-07:00.
+            false => time >= start || time < end,
+        };
+        if !inside {
+            return at;
+        }
+        let mut end_date = local.date_naive();
+        if start > end && time >= start {
+            end_date = end_date.succ_opt().unwrap_or(end_date);
+        }
+        match tz.from_local_datetime(&end_date.and_time(end)).earliest() {
+            Some(end) => end.with_timezone(&Utc),
+            // the end of the window doesn't exist on this day (a DST gap); an hour later does.
+            None => at + Duration::hours(1),
+        }
+    }
+}
+
+/// Drive every sweeper from one loop: work out which channels are due, start a sweep for each,
+/// then sleep until the next one is due or the manager wakes us up because something changed.
+/// Stops once shutdown starts, so no new sweeps begin.
+pub(crate) async fn run_scheduler(manager: Arc<Mutex<SweeperManager>>, wake: Arc<Notify>) {
+    while !CONNECTED.initialized() {
+        tokio::time::sleep(core::time::Duration::from_secs(1)).await;
+    }
+
+    info!("Bot ready, starting scheduler.");
+    loop {
+        let next_due = manager.lock().await.start_due(Utc::now());
+        let sleep_for = match next_due {
+            Some(next) => (next - Utc::now()).to_std().unwrap_or_default(),
+            None => Duration::hours(DEFAULT_INTERVAL_HOURS)
+                .to_std()
+                .expect("1 hour is in range"),
+        };
+        debug!("Scheduler sleeping for {}s", sleep_for.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_for) => {}
+            _ = wake.notified() => {}
+            _ = Shutdown::get().requested() => {
+                info!("Shutting down, stopping scheduler.");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn quiet(start: &str, end: &str) -> QuietWindow {
+        QuietWindow {
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+    }
+
+    #[test]
+    fn runs_inside_a_quiet_window_move_to_its_end() {
+        let window = quiet("01:00", "05:00");
+        let push = |at| window.push_out(utc(at), Tz::UTC);
+        assert_eq!(push("2024-01-15T03:00:00Z"), utc("2024-01-15T05:00:00Z"));
+        assert_eq!(push("2024-01-15T01:00:00Z"), utc("2024-01-15T05:00:00Z"));
+        assert_eq!(push("2024-01-15T05:00:00Z"), utc("2024-01-15T05:00:00Z"));
+        assert_eq!(push("2024-01-15T06:00:00Z"), utc("2024-01-15T06:00:00Z"));
+    }
+
+    #[test]
+    fn quiet_windows_can_wrap_past_midnight() {
+        let window = quiet("22:00", "07:00");
+        let push = |at| window.push_out(utc(at), Tz::UTC);
+        assert_eq!(push("2024-01-15T23:30:00Z"), utc("2024-01-16T07:00:00Z"));
+        assert_eq!(push("2024-01-16T02:00:00Z"), utc("2024-01-16T07:00:00Z"));
+        assert_eq!(push("2024-01-15T12:00:00Z"), utc("2024-01-15T12:00:00Z"));
+        assert_eq!(push("2024-01-15T07:00:00Z"), utc("2024-01-15T07:00:00Z"));
+    }
+
+    #[test]
+    fn quiet_windows_are_in_the_schedules_timezone() {
+        let window = quiet("22:00", "07:00");
+        // 23:00 in New York, five hours behind in winter.
+        assert_eq!(
+            window.push_out(utc("2024-01-16T04:00:00Z"), Tz::America__New_York),
+            utc("2024-01-16T12:00:00Z")
+        );
+        // 17:00 in New York is outside the window, though 22:00 UTC is inside it.
+        assert_eq!(
+            window.push_out(utc("2024-01-15T22:00:00Z"), Tz::America__New_York),
+            utc("2024-01-15T22:00:00Z")
+        );
+    }
+
+    #[test]
+    fn a_quiet_window_ending_in_a_dst_gap_ends_an_hour_later() {
+        // New York skips 02:00-03:00 on 2024-03-10.
+        let window = quiet("01:00", "02:30");
+        assert_eq!(
+            window.push_out(utc("2024-03-10T06:30:00Z"), Tz::America__New_York),
+            utc("2024-03-10T07:30:00Z")
+        );
+    }
+
+    #[test]
+    fn cron_schedules_follow_their_timezone_across_dst() {
+        let schedule = Schedule {
+            cron: Some("0 0 4 * * *".to_string()),
+            timezone: Some("America/New_York".to_string()),
+            ..Schedule::default()
+        };
+        schedule.validate().unwrap();
+        // 04:00 EST, then 04:00 EDT once the clocks go forward.
+        assert_eq!(
+            schedule.next_after(utc("2024-03-08T12:00:00Z")),
+            utc("2024-03-09T09:00:00Z")
+        );
+        assert_eq!(
+            schedule.next_after(utc("2024-03-09T12:00:00Z")),
+            utc("2024-03-10T08:00:00Z")
+        );
+        // and back again in the autumn.
+        assert_eq!(
+            schedule.next_after(utc("2024-11-02T12:00:00Z")),
+            utc("2024-11-03T09:00:00Z")
+        );
+    }
+
+    #[test]
+    fn intervals_skip_the_quiet_window() {
+        let schedule = Schedule {
+            every: Some("1h".to_string().try_into().unwrap()),
+            timezone: Some("Europe/London".to_string()),
+            quiet: Some(quiet("22:00", "07:00")),
+            ..Schedule::default()
+        };
+        schedule.validate().unwrap();
+        // 21:30 in London in summer is 20:30 UTC; an hour later is quiet until 07:00 BST.
+        assert_eq!(
+            schedule.next_after(utc("2024-07-01T20:30:00Z")),
+            utc("2024-07-02T06:00:00Z")
+        );
+        assert_eq!(
+            schedule.next_after(utc("2024-07-01T10:00:00Z")),
+            utc("2024-07-01T11:00:00Z")
+        );
+    }
+
+    #[test]
+    fn schedules_default_to_hourly() {
+        assert_eq!(
+            Schedule::default().next_after(utc("2024-01-15T10:00:00Z")),
+            utc("2024-01-15T11:00:00Z")
+        );
+    }
+}
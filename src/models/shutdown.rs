@@ -0,0 +1,127 @@
+use serenity::client::bridge::gateway::ShardManager;
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::task_tracker::TaskTrackerToken;
+use tokio_util::task::TaskTracker;
+
+/// How long in-flight work gets to finish once shutdown starts.  Kubernetes kills the pod 30
+/// seconds after SIGTERM unless told otherwise, and the goodbye still has to go out after this.
+const SHUTDOWN_DEADLINE_SECS: u64 = 20;
+
+static SHUTDOWN: OnceLock<Shutdown> = OnceLock::new();
+
+/// Coordinates shutting down.  Once it starts no new sweeps are scheduled and no new commands are
+/// taken on, while sweeps and commands already running get until the deadline to finish.  Sweeps
+/// stop between delete chunks, so nothing is left half deleted and unaudited.
+pub(crate) struct Shutdown {
+    token: CancellationToken,
+    in_flight: TaskTracker,
+}
+
+impl Shutdown {
+    /// The bot's one coordinator.
+    pub(crate) fn get() -> &'static Shutdown {
+        SHUTDOWN.get_or_init(|| Shutdown {
+            token: CancellationToken::new(),
+            in_flight: TaskTracker::new(),
+        })
+    }
+
+    /// Start shutting down.  Only the first call does anything.
+    pub(crate) fn begin(&self, reason: &str) {
+        if !self.token.is_cancelled() {
+            info!(reason, "Shutting down.");
+            self.token.cancel();
+        }
+    }
+
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// A token that's cancelled when shutdown starts, and can also be cancelled on its own to stop
+    /// one piece of work early.
+    pub(crate) fn child_token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// Wait until shutdown starts.
+    pub(crate) async fn requested(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Spawn background work that shutdown waits for.
+    pub(crate) fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.in_flight.spawn(task)
+    }
+
+    /// Mark a command as in flight until the returned token is dropped, so shutdown waits for it.
+    /// `None` once shutdown has started, when new commands shouldn't start anything.
+    pub(crate) fn command(&self) -> Option<TaskTrackerToken> {
+        match self.is_shutting_down() {
+            true => None,
+            false => Some(self.in_flight.token()),
+        }
+    }
+}
+
+/// Start shutting down on SIGTERM or ctrl-c.
+pub(crate) async fn watch_signals() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Couldn't listen for SIGTERM: {e}");
+            return;
+        }
+    };
+    let reason = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "ctrl-c",
+        _ = Shutdown::get().requested() => return,
+    };
+    Shutdown::get().begin(reason);
+}
+
+/// Once shutdown starts, wait for in-flight sweeps and commands up to the deadline, say goodbye in
+/// the log channel and disconnect every shard, which lets the client return.
+pub(crate) async fn drain_and_disconnect(
+    shard_manager: Arc<Mutex<ShardManager>>,
+    http: Arc<Http>,
+    log_channel: Option<ChannelId>,
+) {
+    let shutdown = Shutdown::get();
+    shutdown.requested().await;
+
+    shutdown.in_flight.close();
+    info!(
+        in_flight = shutdown.in_flight.len(),
+        "Waiting for in-flight work to finish."
+    );
+    let deadline = Duration::from_secs(SHUTDOWN_DEADLINE_SECS);
+    let goodbye = match tokio::time::timeout(deadline, shutdown.in_flight.wait()).await {
+        Ok(()) => "Shutting down, see you soon.".to_string(),
+        Err(_) => {
+            let left = shutdown.in_flight.len();
+            warn!(left, "Gave up waiting for in-flight work.");
+            format!("Shutting down, {left} sweeps or commands didn't finish in time.")
+        }
+    };
+
+    if let Some(channel) = log_channel {
+        if let Err(e) = channel.say(&http, goodbye).await {
+            error!("Couldn't say goodbye in the log channel: {e}");
+        }
+    }
+    shard_manager.lock().await.shutdown_all().await;
+}
@@ -0,0 +1,486 @@
+use crate::models::discord_error::{with_retry, DiscordError};
+use serenity::async_trait;
+use serenity::http::Http;
+use serenity::model::channel::{Channel, ChannelType, GuildChannel, Message, ReactionType};
+use serenity::model::id::{ChannelId, GuildId, MessageId, RoleId, UserId};
+use serenity::model::user::User;
+use serenity::utils::MessageBuilder;
+use std::sync::Arc;
+
+/// The discord calls a sweep makes.  Sweepers and retention rules go through this rather than
+/// serenity, so they can be run against `FakeStore` in tests.
+#[async_trait]
+pub(crate) trait MessageStore: Send + Sync {
+    /// Up to `limit` of a channel's messages, the newest ones sent before `before` if it's given.
+    async fn messages(
+        &self,
+        channel_id: ChannelId,
+        before: Option<MessageId>,
+        limit: u64,
+    ) -> Result<Vec<Message>, DiscordError>;
+
+    /// Bulk delete messages.  Discord refuses the lot if any of them is over two weeks old.
+    async fn delete_messages(
+        &self,
+        channel_id: ChannelId,
+        message_ids: &[MessageId],
+    ) -> Result<(), DiscordError>;
+
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<(), DiscordError>;
+
+    /// Every active thread in the guild.
+    async fn active_threads(&self, guild_id: GuildId) -> Result<Vec<GuildChannel>, DiscordError>;
+
+    /// The channel's most recently archived public or private threads.
+    async fn archived_threads(
+        &self,
+        channel_id: ChannelId,
+        private: bool,
+    ) -> Result<Vec<GuildChannel>, DiscordError>;
+
+    async fn set_archived(&self, thread_id: ChannelId, archived: bool) -> Result<(), DiscordError>;
+
+    async fn delete_channel(&self, channel_id: ChannelId) -> Result<(), DiscordError>;
+
+    /// The kind of a guild channel, or `None` for any other channel.
+    async fn channel_kind(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<Option<ChannelType>, DiscordError>;
+
+    /// The channel's pinned messages.
+    async fn pins(&self, channel_id: ChannelId) -> Result<Vec<MessageId>, DiscordError>;
+
+    /// The first hundred members who reacted to a message with `reaction`.
+    async fn reaction_users(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        reaction: ReactionType,
+    ) -> Result<Vec<User>, DiscordError>;
+
+    async fn member_roles(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<Vec<RoleId>, DiscordError>;
+
+    async fn say(&self, channel_id: ChannelId, text: String) -> Result<MessageId, DiscordError>;
+}
+
+/// The real thing.  Every call spends from the request budget and is retried when discord has a
+/// hiccup.
+pub(crate) struct DiscordStore {
+    http: Arc<Http>,
+}
+
+impl DiscordStore {
+    pub(crate) fn new(http: Arc<Http>) -> Self {
+        DiscordStore { http }
+    }
+}
+
+#[async_trait]
+impl MessageStore for DiscordStore {
+    async fn messages(
+        &self,
+        channel_id: ChannelId,
+        before: Option<MessageId>,
+        limit: u64,
+    ) -> Result<Vec<Message>, DiscordError> {
+        with_retry(|| {
+            channel_id.messages(&self.http, |b| match before {
+                Some(before) => b.before(before).limit(limit),
+                None => b.limit(limit),
+            })
+        })
+        .await
+    }
+
+    async fn delete_messages(
+        &self,
+        channel_id: ChannelId,
+        message_ids: &[MessageId],
+    ) -> Result<(), DiscordError> {
+        with_retry(|| channel_id.delete_messages(&self.http, message_ids)).await
+    }
+
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<(), DiscordError> {
+        with_retry(|| channel_id.delete_message(&self.http, message_id)).await
+    }
+
+    async fn active_threads(&self, guild_id: GuildId) -> Result<Vec<GuildChannel>, DiscordError> {
+        let active = with_retry(|| guild_id.get_active_threads(&self.http)).await?;
+        Ok(active.threads)
+    }
+
+    async fn archived_threads(
+        &self,
+        channel_id: ChannelId,
+        private: bool,
+    ) -> Result<Vec<GuildChannel>, DiscordError> {
+        // serenity takes `before` as an integer where discord wants a timestamp, so only the first
+        // page is read.
+        let archived = match private {
+            true => {
+                with_retry(|| channel_id.get_archived_private_threads(&self.http, None, Some(100)))
+                    .await?
+            }
+            false => {
+                with_retry(|| channel_id.get_archived_public_threads(&self.http, None, Some(100)))
+                    .await?
+            }
+        };
+        Ok(archived.threads)
+    }
+
+    async fn set_archived(&self, thread_id: ChannelId, archived: bool) -> Result<(), DiscordError> {
+        with_retry(|| thread_id.edit_thread(&self.http, |t| t.archived(archived))).await?;
+        Ok(())
+    }
+
+    async fn delete_channel(&self, channel_id: ChannelId) -> Result<(), DiscordError> {
+        with_retry(|| channel_id.delete(&self.http)).await?;
+        Ok(())
+    }
+
+    async fn channel_kind(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<Option<ChannelType>, DiscordError> {
+        match with_retry(|| channel_id.to_channel(&self.http)).await? {
+            Channel::Guild(channel) => Ok(Some(channel.kind)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn pins(&self, channel_id: ChannelId) -> Result<Vec<MessageId>, DiscordError> {
+        let pins = with_retry(|| channel_id.pins(&self.http)).await?;
+        Ok(pins.into_iter().map(|m| m.id).collect())
+    }
+
+    async fn reaction_users(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        reaction: ReactionType,
+    ) -> Result<Vec<User>, DiscordError> {
+        with_retry(|| {
+            channel_id.reaction_users(&self.http, message_id, reaction.clone(), Some(100), None)
+        })
+        .await
+    }
+
+    async fn member_roles(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<Vec<RoleId>, DiscordError> {
+        let member = with_retry(|| guild_id.member(&self.http, user_id)).await?;
+        Ok(member.roles)
+    }
+
+    async fn say(&self, channel_id: ChannelId, text: String) -> Result<MessageId, DiscordError> {
+        let text = MessageBuilder::new().push(text).build();
+        let message = with_retry(|| channel_id.say(&self.http, &text)).await?;
+        Ok(message.id)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    use super::MessageStore;
+    use crate::models::discord_error::DiscordError;
+    use crate::models::sweeper::{snowflake_at, BULK_DELETE_MAX_AGE_DAYS};
+    use chrono::{DateTime, Duration, Utc};
+    use serde_json::json;
+    use serenity::async_trait;
+    use serenity::model::channel::{ChannelType, GuildChannel, Message, ReactionType};
+    use serenity::model::id::{ChannelId, GuildId, MessageId, RoleId, UserId};
+    use serenity::model::user::User;
+    use std::collections::{BTreeMap, HashMap, VecDeque};
+    use std::sync::Mutex;
+
+    /// An in-memory guild that behaves like discord where the sweeper cares: pages come back
+    /// newest first, and a bulk delete with a message over two weeks old fails with 50034.
+    #[derive(Default)]
+    pub(crate) struct FakeStore {
+        state: Mutex<FakeState>,
+    }
+
+    #[derive(Default)]
+    pub(crate) struct FakeState {
+        pub(crate) messages: HashMap<ChannelId, BTreeMap<MessageId, Message>>,
+        pub(crate) kinds: HashMap<ChannelId, ChannelType>,
+        /// Errors the next bulk deletes fail with, before anything else is checked.
+        pub(crate) bulk_errors: VecDeque<DiscordError>,
+        /// Messages that fail to delete with an error, alone or in a bulk delete.
+        pub(crate) undeletable: HashMap<MessageId, DiscordError>,
+        /// Every bulk delete attempted, successful or not.
+        pub(crate) bulk_deletes: Vec<Vec<MessageId>>,
+        /// Who reacted to each message.
+        pub(crate) reactors: HashMap<MessageId, Vec<UserId>>,
+        /// Members' roles, or the error looking them up fails with.  Anyone not here has left.
+        pub(crate) roles: HashMap<UserId, Result<Vec<RoleId>, DiscordError>>,
+        pub(crate) pages_read: u32,
+        pub(crate) said: Vec<(ChannelId, String)>,
+    }
+
+    impl FakeStore {
+        pub(crate) fn state(&self) -> std::sync::MutexGuard<'_, FakeState> {
+            self.state.lock().unwrap()
+        }
+
+        /// Add a message sent at `at`.  `seq` tells apart messages sent in the same millisecond.
+        pub(crate) fn post(
+            &self,
+            channel_id: ChannelId,
+            author: u64,
+            at: DateTime<Utc>,
+            seq: u64,
+            pinned: bool,
+        ) -> MessageId {
+            let id = MessageId(snowflake_at(at).0 + seq);
+            let message: Message = serde_json::from_value(json!({
+                "id": id.to_string(),
+                "channel_id": channel_id.to_string(),
+                "author": {
+                    "id": author.to_string(),
+                    "username": format!("member{author}"),
+                    "discriminator": "0001",
+                    "avatar": null,
+                },
+                "content": "hello",
+                "timestamp": at.to_rfc3339(),
+                "edited_timestamp": null,
+                "tts": false,
+                "mention_everyone": false,
+                "mentions": [],
+                "mention_roles": [],
+                "attachments": [],
+                "embeds": [],
+                "pinned": pinned,
+                "type": 0,
+            }))
+            .unwrap();
+            self.state()
+                .messages
+                .entry(channel_id)
+                .or_default()
+                .insert(id, message);
+            id
+        }
+
+        /// React to a message with `emoji` as each of `users`.
+        pub(crate) fn react(
+            &self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+            emoji: &str,
+            users: &[u64],
+        ) {
+            let reaction = serde_json::from_value(json!({
+                "count": users.len(),
+                "me": false,
+                "emoji": {"id": null, "name": emoji},
+            }))
+            .unwrap();
+            let mut state = self.state();
+            if let Some(message) = state
+                .messages
+                .get_mut(&channel_id)
+                .and_then(|m| m.get_mut(&message_id))
+            {
+                message.reactions.push(reaction);
+            }
+            state
+                .reactors
+                .insert(message_id, users.iter().map(|u| UserId(*u)).collect());
+        }
+
+        pub(crate) fn remaining(&self, channel_id: ChannelId) -> Vec<MessageId> {
+            self.state()
+                .messages
+                .get(&channel_id)
+                .map(|m| m.keys().copied().collect())
+                .unwrap_or_default()
+        }
+    }
+
+    #[async_trait]
+    impl MessageStore for FakeStore {
+        async fn messages(
+            &self,
+            channel_id: ChannelId,
+            before: Option<MessageId>,
+            limit: u64,
+        ) -> Result<Vec<Message>, DiscordError> {
+            let mut state = self.state();
+            state.pages_read += 1;
+            let Some(messages) = state.messages.get(&channel_id) else {
+                return Err(DiscordError::UnknownChannel);
+            };
+            Ok(messages
+                .values()
+                .rev()
+                .filter(|m| before.is_none_or(|before| m.id < before))
+                .take(limit.min(100) as usize)
+                .cloned()
+                .collect())
+        }
+
+        async fn delete_messages(
+            &self,
+            channel_id: ChannelId,
+            message_ids: &[MessageId],
+        ) -> Result<(), DiscordError> {
+            // serenity deletes a lone message through the single message endpoint.
+            if let [message_id] = message_ids {
+                return self.delete_message(channel_id, *message_id).await;
+            }
+            let mut state = self.state();
+            state.bulk_deletes.push(message_ids.to_vec());
+            if let Some(e) = state.bulk_errors.pop_front() {
+                return Err(e);
+            }
+            if let Some(e) = message_ids.iter().find_map(|id| state.undeletable.get(id)) {
+                return Err(e.clone());
+            }
+            let cutoff = Utc::now() - Duration::days(BULK_DELETE_MAX_AGE_DAYS);
+            let messages = state.messages.entry(channel_id).or_default();
+            if message_ids
+                .iter()
+                .filter_map(|id| messages.get(id))
+                .any(|m| *m.timestamp < cutoff)
+            {
+                return Err(DiscordError::TooOldToBulkDelete);
+            }
+            for id in message_ids {
+                messages.remove(id);
+            }
+            Ok(())
+        }
+
+        async fn delete_message(
+            &self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+        ) -> Result<(), DiscordError> {
+            let mut state = self.state();
+            if let Some(e) = state.undeletable.get(&message_id) {
+                return Err(e.clone());
+            }
+            match state
+                .messages
+                .entry(channel_id)
+                .or_default()
+                .remove(&message_id)
+            {
+                Some(_) => Ok(()),
+                None => Err(DiscordError::UnknownMessage),
+            }
+        }
+
+        async fn active_threads(
+            &self,
+            _guild_id: GuildId,
+        ) -> Result<Vec<GuildChannel>, DiscordError> {
+            Ok(vec![])
+        }
+
+        async fn archived_threads(
+            &self,
+            _channel_id: ChannelId,
+            _private: bool,
+        ) -> Result<Vec<GuildChannel>, DiscordError> {
+            Ok(vec![])
+        }
+
+        async fn set_archived(
+            &self,
+            _thread_id: ChannelId,
+            _archived: bool,
+        ) -> Result<(), DiscordError> {
+            Ok(())
+        }
+
+        async fn delete_channel(&self, channel_id: ChannelId) -> Result<(), DiscordError> {
+            self.state().messages.remove(&channel_id);
+            Ok(())
+        }
+
+        async fn channel_kind(
+            &self,
+            channel_id: ChannelId,
+        ) -> Result<Option<ChannelType>, DiscordError> {
+            Ok(Some(
+                self.state()
+                    .kinds
+                    .get(&channel_id)
+                    .copied()
+                    .unwrap_or(ChannelType::Text),
+            ))
+        }
+
+        async fn pins(&self, channel_id: ChannelId) -> Result<Vec<MessageId>, DiscordError> {
+            Ok(self
+                .state()
+                .messages
+                .get(&channel_id)
+                .map(|m| m.values().filter(|m| m.pinned).map(|m| m.id).collect())
+                .unwrap_or_default())
+        }
+
+        async fn reaction_users(
+            &self,
+            _channel_id: ChannelId,
+            message_id: MessageId,
+            _reaction: ReactionType,
+        ) -> Result<Vec<User>, DiscordError> {
+            let state = self.state();
+            let reactors = state.reactors.get(&message_id).cloned().unwrap_or_default();
+            Ok(reactors
+                .into_iter()
+                .map(|id| {
+                    serde_json::from_value(json!({
+                        "id": id.to_string(),
+                        "username": format!("member{id}"),
+                        "discriminator": "0001",
+                        "avatar": null,
+                    }))
+                    .unwrap()
+                })
+                .collect())
+        }
+
+        async fn member_roles(
+            &self,
+            _guild_id: GuildId,
+            user_id: UserId,
+        ) -> Result<Vec<RoleId>, DiscordError> {
+            self.state()
+                .roles
+                .get(&user_id)
+                .cloned()
+                .unwrap_or(Err(DiscordError::UnknownMember))
+        }
+
+        async fn say(
+            &self,
+            channel_id: ChannelId,
+            text: String,
+        ) -> Result<MessageId, DiscordError> {
+            let mut state = self.state();
+            state.said.push((channel_id, text));
+            Ok(MessageId(state.said.len() as u64))
+        }
+    }
+}
@@ -1,58 +1,77 @@
-use async_recursion::async_recursion;
 use async_stream::try_stream;
 use chrono::{DateTime, Duration, Utc};
 use futures::stream::StreamExt;
-use serenity::futures::{Stream, TryStreamExt};
-use serenity::http::{Http, HttpError};
-use serenity::model::channel::Message;
-use serenity::model::id::{ChannelId, GuildId, MessageId};
+use serenity::futures::Stream;
+use serenity::model::channel::{ChannelType, GuildChannel, Message};
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
 use serenity::prelude::TypeMapKey;
-use serenity::utils::MessageBuilder;
 
-use crate::CONNECTED;
+use crate::commands::llama::OllamaApi;
+use crate::models::archive::Archiver;
+use crate::models::config::ChannelConfig;
+use crate::models::db::{Database, Deletion, SweepRun};
+use crate::models::digest::{split_for_discord, write_digest};
+use crate::models::discord_error::{DiscordError, ErrorPolicy};
+use crate::models::dry_run::DryRunReport;
+use crate::models::rules::{Decision, RuleSet};
+use crate::models::shutdown::Shutdown;
+use crate::models::store::MessageStore;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
 use std::ops::Deref;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use std::thread::sleep;
-use std::{env, future};
-use tokio::sync::{mpsc, watch, Mutex};
+use tokio::sync::watch;
 use tokio::time::Instant;
-use tracing::{debug, error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
-pub(crate) async fn run_sweeper(mut sweeper: Sweeper, once: bool) {
-    while !CONNECTED.initialized() {
-        sleep(core::time::Duration::from_secs(1));
-    }
-
-    info!("Bot ready, starting sweep loop.");
-    loop {
-        let start = Instant::now();
-        sweeper.sweep_messages().await;
-        info!(
-            task_millis = start.elapsed().as_millis(),
-            stats = ?sweeper.stats,
-            "Ran sweeper for {}. Sleeping for 1 hour.",
-            sweeper.channel_id.0
-        );
+/// Discord refuses to bulk delete messages older than two weeks.  Leave some slack so a message
+/// that ages out between listing and deleting doesn't fail the whole chunk.
+pub(crate) const BULK_DELETE_MAX_AGE_DAYS: i64 = 14;
+pub(crate) const BULK_DELETE_SLACK_MINUTES: i64 = 10;
+/// The most messages discord returns in one page.
+const MESSAGE_PAGE_SIZE: u64 = 100;
+/// Protected message kind for posted digests.
+const DIGEST_KIND: &str = "digest";
+/// Discord's epoch, the first millisecond of 2015, in unix milliseconds.
+const DISCORD_EPOCH_MILLIS: i64 = 1_420_070_400_000;
 
-        if once {
-            info!("Instantiated with once = true, exiting 1-hr loop logic.");
-            return;
-        }
-        tokio::time::sleep(Duration::hours(1).to_std().expect("1 hour is in range")).await;
-    }
+/// Run one sweep of a channel.  The scheduler decides when.
+pub(crate) async fn run_sweeper(sweeper: &mut Sweeper) {
+    let start = Instant::now();
+    sweeper.sweep_messages().await;
+    info!(
+        task_millis = start.elapsed().as_millis(),
+        stats = ?sweeper.stats,
+        "Ran sweeper for {}.",
+        sweeper.channel_id.0
+    );
 }
 
 pub(crate) struct Sweeper {
-    http: Arc<Http>,
+    store: Arc<dyn MessageStore>,
     guild_id: GuildId,
     channel_id: ChannelId,
     max_message_age: Duration,
     dry_run: bool,
+    config: ChannelConfig,
     log_channel: Option<ChannelId>,
     stats: Stats,
     stats_tx: watch::Sender<Stats>,
+    db: Arc<Database>,
+    /// Messages too old to bulk delete, waiting to be deleted one at a time.  Anything left over
+    /// when a run's budget is spent is picked up by the next run.  The queue is kept in the
+    /// database too, so it outlasts restarts and the sweeper being rebuilt.
+    old_messages: BTreeMap<MessageId, Deletion>,
+    /// Whether this run has checked the queued old messages against the channel's rules, which
+    /// can have changed, or the messages been pinned or kept, since they were queued.
+    old_checked: bool,
+    /// The sweep history record of the run in progress, for the deletion audit log.
+    run_id: Option<i64>,
+    /// Cancelled when the bot shuts down or the sweeper is taken off the schedule.
+    stop: CancellationToken,
 }
 
 #[derive(Debug, Clone)]
@@ -62,6 +81,39 @@ pub(crate) struct Stats {
     pub(crate) runs: u32,
     pub(crate) last_run: u32,
     pub(crate) all_runs: u32,
+    /// Messages older than the bulk delete window that were deleted one at a time.
+    pub(crate) old_deleted: u32,
+    /// Messages older than the bulk delete window still waiting to be deleted.
+    pub(crate) old_pending: u32,
+    /// Threads swept in the last run.  Their messages count towards the channel's runs.
+    pub(crate) threads_swept: u32,
+    /// Emptied threads deleted since the bot started.
+    pub(crate) threads_deleted: u32,
+}
+
+impl Stats {
+    pub(crate) fn new(channel_id: ChannelId) -> Self {
+        Stats {
+            channel_id,
+            started: Utc::now(),
+            runs: 0,
+            last_run: 0,
+            all_runs: 0,
+            old_deleted: 0,
+            old_pending: 0,
+            threads_swept: 0,
+            threads_deleted: 0,
+        }
+    }
+}
+
+/// What purging one member's messages did, across every channel it went through.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PurgeReport {
+    pub(crate) channels: u32,
+    /// Messages by the member that were found, whether or not they could be deleted.
+    pub(crate) found: u32,
+    pub(crate) run: SweepRun,
 }
 
 pub(crate) struct StatsReceiver;
@@ -72,19 +124,16 @@ impl TypeMapKey for StatsReceiver {
 
 impl Sweeper {
     pub(crate) fn new(
-        http: Http,
+        store: Arc<dyn MessageStore>,
         guild_id: GuildId,
-        channel_id: ChannelId,
-        max_message_age: Duration,
+        channel: &ChannelConfig,
         dry_run: bool,
+        db: Arc<Database>,
     ) -> (Self, watch::Receiver<Stats>) {
-        let stats = Stats {
-            channel_id,
-            started: Utc::now(),
-            runs: 0,
-            last_run: 0,
-            all_runs: 0,
-        };
+        let channel_id = channel.channel_id();
+        let old_messages = load_old_messages(&db, channel_id);
+        let mut stats = Stats::new(channel_id);
+        stats.old_pending = old_messages.len() as u32;
 
         let (tx, rx) = watch::channel(stats.clone());
 
@@ -98,147 +147,774 @@ impl Sweeper {
 
         (
             Sweeper {
-                http: Arc::new(http),
+                store,
                 guild_id,
                 channel_id,
                 log_channel,
-                max_message_age,
-                dry_run,
+                max_message_age: channel.max_message_age(),
+                dry_run: dry_run || channel.dry_run,
+                config: channel.clone(),
                 stats,
                 stats_tx: tx,
+                db,
+                old_messages,
+                run_id: None,
+                old_checked: false,
+                stop: Shutdown::get().child_token(),
             },
             rx,
         )
     }
 
-    #[async_recursion]
     async fn sweep_messages(&mut self) {
-        let cutoff_time = Utc::now() - self.max_message_age;
-
-        let success_count = Arc::new(AtomicU32::new(0));
-
-        if let Ok(threads) = self.guild_id.get_active_threads(self.http.clone()).await {
-            for thread in threads.threads {
-                if thread.parent_id == Some(self.channel_id) {
-                    info!("Thread {} is in scope for sweeping", thread.name);
-                    // we already know DISCORD_TOKEN is valid since we expect it in main.rs when starting
-                    let token = env::var("DISCORD_TOKEN").unwrap();
-                    let (mut sweeper, _stats) = Sweeper::new(
-                        Http::new(&token),
-                        thread.guild_id,
-                        thread.id,
-                        Duration::days(1),
-                        false,
-                    );
-                    // Start sweeper on thread.
-                    sweeper.sweep_messages().await;
-                    if thread.message_count.unwrap() == 0 {
-                        info!("Deleting thread {}", thread.name);
-                        if let Err(e) = thread.delete(self.http.clone()).await {
-                            error!(
-                                "Attempted to delete thread {} but failed: {:#?}",
-                                thread.name, e
-                            );
-                        }
+        let run_started = Utc::now();
+        let run_id = match self.db.start_run(self.channel_id, run_started) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                error!("Couldn't record the start of a sweep run: {e:#}");
+                None
+            }
+        };
+        self.run_id = run_id;
+        let mut run = SweepRun::default();
+
+        let mut dry_run = DryRunReport::new(self.guild_id, self.channel_id);
+
+        self.sweep_threads(run_started, &mut run, &mut dry_run)
+            .await;
+        // a forum channel's messages all live in its posts, which are threads.
+        if !self.is_forum().await {
+            self.sweep_expired(run_started, &mut run, &mut dry_run)
+                .await;
+        }
+        self.delete_old_messages(&mut run).await;
+
+        if self.dry_run && dry_run.would_delete > 0 {
+            dry_run.errors = run.errors;
+            self.report(dry_run.render()).await;
+        }
+
+        if let Some(run_id) = run_id {
+            if let Err(e) = self.db.finish_run(run_id, Utc::now(), &run) {
+                error!("Couldn't record the end of a sweep run: {e:#}");
+            }
+        }
+
+        self.stats.runs += 1;
+        self.stats.last_run = run.deleted;
+        self.stats.all_runs += run.deleted;
+        // nobody is listening once the channel has been removed or rebuilt mid-sweep.
+        self.stats_tx.send_replace(self.stats.clone());
+    }
+
+    /// Work out what a sweep would delete right now, without deleting anything.  Only makes sense
+    /// on a dry run sweeper.
+    pub(crate) async fn preview(&mut self) -> DryRunReport {
+        let now = Utc::now();
+        let mut run = SweepRun::default();
+        let mut dry_run = DryRunReport::new(self.guild_id, self.channel_id);
+        self.sweep_threads(now, &mut run, &mut dry_run).await;
+        if !self.is_forum().await {
+            self.sweep_expired(now, &mut run, &mut dry_run).await;
+        }
+        dry_run.errors = run.errors;
+        dry_run
+    }
+
+    /// A sweeper for purging one member's messages from a channel.  Its queue of old messages
+    /// starts empty, since the channel's holds everyone's.
+    pub(crate) fn purger(
+        store: Arc<dyn MessageStore>,
+        guild_id: GuildId,
+        channel: &ChannelConfig,
+        dry_run: bool,
+        db: Arc<Database>,
+    ) -> Self {
+        let (mut sweeper, _stats) = Sweeper::new(store, guild_id, channel, dry_run, db);
+        sweeper.old_messages.clear();
+        sweeper.stats.old_pending = 0;
+        sweeper
+    }
+
+    /// Delete every message `author` has sent in the channel and its threads, however old, and add
+    /// what happened to `report`.  Messages too old for bulk delete are deleted one at a time
+    /// straight away, so this can take a while.  The queue of old messages isn't touched.
+    pub(crate) async fn purge_author(
+        &mut self,
+        author: UserId,
+        reason: &str,
+        report: &mut PurgeReport,
+    ) {
+        self.purge_channel(author, reason, report).await;
+        for thread in self.threads().await {
+            if Shutdown::get().is_shutting_down() {
+                break;
+            }
+            let mut sweeper = self.thread_sweeper(thread.id);
+            sweeper.old_messages.clear();
+            let archived = thread.thread_metadata.is_some_and(|m| m.archived);
+            if archived
+                && !self.dry_run
+                && !self.set_archived(&thread, false, &mut report.run).await
+            {
+                continue;
+            }
+            sweeper.purge_channel(author, reason, report).await;
+            if archived && !self.dry_run {
+                self.set_archived(&thread, true, &mut report.run).await;
+            }
+        }
+    }
+
+    async fn purge_channel(&mut self, author: UserId, reason: &str, report: &mut PurgeReport) {
+        let run = &mut report.run;
+        let mut messages = vec![];
+        let mut read_error = None;
+        let mut stream = self.message_stream(Utc::now());
+        while let Some(message) = stream.next().await {
+            match message {
+                Ok(message) => {
+                    run.scanned += 1;
+                    if message.author.id == author {
+                        messages.push(message);
                     }
                 }
+                Err(e) => {
+                    read_error = Some(e);
+                    break;
+                }
+            }
+        }
+        drop(stream);
+        // carry on with what was found, a second purge can pick up the rest.
+        if let Some(e) = read_error {
+            run.errors += 1;
+            self.report_error("read messages", &e).await;
+        }
+        report.channels += 1;
+        report.found += messages.len() as u32;
+        if self.dry_run {
+            info!(
+                count = messages.len(),
+                "Dry run, not purging {}'s messages in {}", author, self.channel_id
+            );
+            return;
+        }
+
+        let mut pending: HashMap<MessageId, Deletion> = messages
+            .iter()
+            .map(|m| {
+                let deletion = Deletion {
+                    message_id: m.id,
+                    channel_id: m.channel_id,
+                    author_id: Some(author),
+                    sent_at: *m.timestamp,
+                    reason: reason.to_string(),
+                };
+                (m.id, deletion)
+            })
+            .collect();
+        let bulk_cutoff = Utc::now() - Duration::days(BULK_DELETE_MAX_AGE_DAYS)
+            + Duration::minutes(BULK_DELETE_SLACK_MINUTES);
+        let (old, recent): (Vec<Message>, Vec<Message>) = messages
+            .into_iter()
+            .partition(|m| m.timestamp.deref() < &bulk_cutoff);
+        for chunk in recent.chunks(100) {
+            if self.stopping() {
+                return;
+            }
+            let chunk: Vec<MessageId> = chunk.iter().map(|m| m.id).collect();
+            self.delete_chunk(chunk, &mut pending, run).await;
+        }
+        // deleted here rather than through the queue of old messages, which holds everyone's.
+        if self.stopping() {
+            return;
+        }
+        let old: Vec<MessageId> = old.iter().map(|m| m.id).collect();
+        self.delete_each(old, &mut pending, run).await;
+    }
+
+    /// Sweep the channel's threads with the channel's thread policy, adding what they did to this
+    /// run.  An archived thread can't be changed, so it's unarchived for the sweep and archived
+    /// again afterwards, unless it was emptied and deleted.
+    async fn sweep_threads(
+        &mut self,
+        run_started: DateTime<Utc>,
+        run: &mut SweepRun,
+        dry_run: &mut DryRunReport,
+    ) {
+        if !self.config.threads.sweep {
+            return;
+        }
+
+        let mut swept = 0;
+        for thread in self.threads().await {
+            if Shutdown::get().is_shutting_down() {
+                break;
+            }
+            let mut sweeper = self.thread_sweeper(thread.id);
+            let archived = thread.thread_metadata.is_some_and(|m| m.archived);
+            if archived {
+                // nothing in a thread created after the cutoff can have expired yet.
+                if thread.id.created_at().deref() >= &(run_started - sweeper.max_message_age) {
+                    continue;
+                }
+                if !self.dry_run && !self.set_archived(&thread, false, run).await {
+                    continue;
+                }
+            }
+
+            info!("Sweeping thread {}", thread.name);
+            sweeper.sweep_expired(run_started, run, dry_run).await;
+            sweeper.delete_old_messages(run).await;
+            self.stats.old_deleted += sweeper.stats.old_deleted;
+            swept += 1;
+
+            if self.dry_run {
+                continue;
+            }
+            if self.config.threads.delete_empty && sweeper.is_empty().await {
+                info!("Deleting empty thread {}", thread.name);
+                match self.store.delete_channel(thread.id).await {
+                    Ok(_) => {
+                        self.stats.threads_deleted += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        run.errors += 1;
+                        self.report_error("delete an empty thread", &e).await;
+                    }
+                }
+            }
+            if archived {
+                self.set_archived(&thread, true, run).await;
+            }
+        }
+        self.stats.threads_swept = swept;
+    }
+
+    /// Archive or unarchive one of the channel's threads.  Returns whether it worked.
+    async fn set_archived(
+        &self,
+        thread: &GuildChannel,
+        archived: bool,
+        run: &mut SweepRun,
+    ) -> bool {
+        match self.store.set_archived(thread.id, archived).await {
+            Ok(_) => true,
+            Err(e) => {
+                run.errors += 1;
+                let action = match archived {
+                    true => "archive a thread again",
+                    false => "unarchive a thread",
+                };
+                match e.is_misconfiguration() {
+                    true => self.report_error(action, &e).await,
+                    false => warn!("Couldn't {action} ({}): {e}", thread.name),
+                }
+                false
+            }
+        }
+    }
+
+    /// The channel's threads: the active ones, plus the most recently archived public and private
+    /// ones if the thread policy asks for them.  A forum channel's posts are its threads.
+    async fn threads(&self) -> Vec<GuildChannel> {
+        let mut threads = vec![];
+        match self.store.active_threads(self.guild_id).await {
+            Ok(active) => threads.extend(
+                active
+                    .into_iter()
+                    .filter(|t| t.parent_id == Some(self.channel_id)),
+            ),
+            Err(e) => warn!("Couldn't list active threads: {e}"),
+        }
+        if !self.config.threads.archived {
+            return threads;
+        }
+
+        // only the newest archived threads are listed.  Emptied threads get deleted, which makes
+        // room for older ones.
+        match self.store.archived_threads(self.channel_id, false).await {
+            Ok(archived) => threads.extend(archived),
+            Err(e) => warn!("Couldn't list archived public threads: {e}"),
+        }
+        match self.store.archived_threads(self.channel_id, true).await {
+            Ok(archived) => threads.extend(archived),
+            // forum channels don't have private threads, and listing them needs manage threads.
+            Err(e) => debug!("Couldn't list archived private threads: {e}"),
+        }
+        threads
+    }
+
+    /// A sweeper for one of this channel's threads.  It shares the channel's connection and dry
+    /// run setting, and its results are added to the channel's.
+    fn thread_sweeper(&self, thread_id: ChannelId) -> Sweeper {
+        let config = self.config.thread_config(thread_id);
+        let stats = Stats::new(thread_id);
+        let (stats_tx, _) = watch::channel(stats.clone());
+        Sweeper {
+            store: self.store.clone(),
+            guild_id: self.guild_id,
+            channel_id: thread_id,
+            log_channel: self.log_channel,
+            max_message_age: config.max_message_age(),
+            dry_run: self.dry_run,
+            config,
+            stats,
+            stats_tx,
+            db: self.db.clone(),
+            old_messages: load_old_messages(&self.db, thread_id),
+            run_id: self.run_id,
+            old_checked: false,
+            stop: self.stop.clone(),
+        }
+    }
+
+    async fn is_forum(&self) -> bool {
+        matches!(
+            self.store.channel_kind(self.channel_id).await,
+            Ok(Some(ChannelType::Forum))
+        )
+    }
+
+    /// Whether the channel has no messages left.  Errs on the side of not empty.
+    async fn is_empty(&self) -> bool {
+        match self.store.messages(self.channel_id, None, 1).await {
+            Ok(messages) => messages.is_empty(),
+            Err(e) => {
+                warn!("Couldn't check whether {} is empty: {e}", self.channel_id);
+                false
             }
         }
+    }
 
-        info!(%cutoff_time, "Sweeping expired messages.");
+    /// Find the channel's expired messages, archive them if asked to, and delete them.  Messages
+    /// too old for bulk delete are queued for `delete_old_messages`.
+    async fn sweep_expired(
+        &mut self,
+        run_started: DateTime<Utc>,
+        run: &mut SweepRun,
+        dry_run: &mut DryRunReport,
+    ) {
+        let cutoff_time = run_started - self.max_message_age;
+        self.old_checked = false;
 
-        let message_ids: Vec<MessageId> = self
-            .message_stream()
-            .try_take_while(|message| future::ready(Ok(message.timestamp.deref() < &cutoff_time)))
+        let rules = match RuleSet::prepare(
+            &self.config.rules,
+            self.config.keep.as_ref(),
+            self.store.clone(),
+            self.guild_id,
+            self.channel_id,
+            run_started,
+            self.max_message_age,
+        )
+        .await
+        {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("Couldn't prepare retention rules, not sweeping: {e}");
+                run.errors += 1;
+                self.report(format!(
+                    "Not sweeping {}: couldn't prepare retention rules: {}",
+                    self.channel_id, e
+                ))
+                .await;
+                return;
+            }
+        };
+        let rules = match self.db.protected_messages(self.channel_id) {
+            Ok(protected) => rules.protect(protected.into_iter().map(|(id, _)| id)),
+            Err(e) => {
+                error!("Couldn't load protected messages, not sweeping: {e:#}");
+                run.errors += 1;
+                return;
+            }
+        };
+        let stream_cutoff = rules.stream_cutoff();
+
+        info!(%cutoff_time, %stream_cutoff, "Sweeping expired messages.");
+
+        let scanned = Arc::new(AtomicU32::new(0));
+        let pinned = Arc::new(AtomicU32::new(0));
+        let dry_run = std::sync::Mutex::new(dry_run);
+        let read_error = std::sync::Mutex::new(None);
+        let still_expired = std::sync::Mutex::new(HashSet::new());
+        let messages: Vec<(Message, String)> = self
+            .message_stream(stream_cutoff)
             .filter_map(|m_result| async {
-                let message = m_result.unwrap();
-                let dry_run = self.dry_run;
-                let success_count = success_count.clone();
-                match dry_run {
-                    true => {
-                        debug!(%message.id, "Skipped adding message to delete queue due to dry run.");
+                let message = match m_result {
+                    Ok(message) => message,
+                    Err(e) => {
+                        // the stream ends here, what was read so far is still swept.
+                        *read_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+                        return None;
+                    }
+                };
+                scanned.fetch_add(1, Ordering::SeqCst);
+                let decision = rules.evaluate(&message).await;
+                if self.old_messages.contains_key(&message.id) {
+                    // already archived and queued for one-at-a-time deletion by an earlier run.
+                    if matches!(decision, Decision::Delete(_)) {
+                        still_expired.lock().unwrap_or_else(|e| e.into_inner()).insert(message.id);
+                    }
+                    return None;
+                }
+                if self.dry_run {
+                    dry_run.lock().unwrap_or_else(|e| e.into_inner()).record(&message, &decision);
+                }
+                match decision {
+                    Decision::NotExpired(rule) => {
+                        debug!(%message.id, %rule, "Keeping message.");
                         None
                     }
-                    false => {
-                        let success_count = success_count;
-                        success_count.fetch_add(1, Ordering::SeqCst);
+                    Decision::Keep(rule) => {
+                        debug!(%message.id, %rule, "Keeping message.");
                         if message.pinned {
-                            debug!(%message.id, "message is pinned, skipping delete.");
-                            return None;
+                            pinned.fetch_add(1, Ordering::SeqCst);
                         }
-                        Some(message.id)
+                        None
+                    }
+                    Decision::Delete(rule) if self.dry_run => {
+                        debug!(%message.id, %rule, "Skipped adding message to delete queue due to dry run.");
+                        None
+                    }
+                    Decision::Delete(rule) => {
+                        debug!(%message.id, %rule, "Adding message to delete queue.");
+                        Some((message, rule))
                     }
                 }
             })
             .collect()
             .await;
 
-        let success_count = success_count.load(Ordering::SeqCst);
+        run.scanned += scanned.load(Ordering::SeqCst);
+        run.pinned += pinned.load(Ordering::SeqCst);
+        match read_error.into_inner().unwrap_or_else(|e| e.into_inner()) {
+            Some(e) => {
+                run.errors += 1;
+                self.report_error("read messages", &e).await;
+            }
+            None => self.recheck_old(
+                still_expired
+                    .into_inner()
+                    .unwrap_or_else(|e| e.into_inner()),
+            ),
+        }
 
-        let total_messages = message_ids.len();
+        if let Some(digest) = &self.config.digest {
+            if !self.dry_run && !messages.is_empty() {
+                self.post_digest(digest.channel_id(), &messages, run).await;
+            }
+        }
+
+        let archiver = match self.config.archive && !messages.is_empty() {
+            true => match Archiver::from_env() {
+                Ok(archiver) => Some(archiver),
+                Err(e) => {
+                    error!("Archiving is enabled but unavailable, not deleting anything: {e:#}");
+                    run.errors += 1;
+                    self.report(format!(
+                        "Not sweeping {}: archiving is enabled but unavailable: {:#}",
+                        self.channel_id, e
+                    ))
+                    .await;
+                    return;
+                }
+            },
+            false => None,
+        };
+
+        let mut pending: HashMap<MessageId, Deletion> = messages
+            .iter()
+            .map(|(m, reason)| {
+                let deletion = Deletion {
+                    message_id: m.id,
+                    channel_id: m.channel_id,
+                    author_id: Some(m.author.id),
+                    sent_at: *m.timestamp,
+                    reason: reason.clone(),
+                };
+                (m.id, deletion)
+            })
+            .collect();
+        let messages: Vec<Message> = messages.into_iter().map(|(m, _)| m).collect();
+
+        let bulk_cutoff = Utc::now() - Duration::days(BULK_DELETE_MAX_AGE_DAYS)
+            + Duration::minutes(BULK_DELETE_SLACK_MINUTES);
+        let total_messages = messages.len();
         debug!("Preparing to issue deletes for {total_messages} messages.");
-        for chunk in message_ids.chunks(100) {
-            debug!("Issuing chunk delete for {} messages.", chunk.len());
-            if let Err(e) = self
-                .channel_id
-                .delete_messages(self.http.clone(), chunk.clone())
-                .await
-            {
-                if let serenity::Error::Http(boxed) = &e {
-                    if let HttpError::UnsuccessfulRequest(er) = boxed.deref() {
-                        match er.error.code {
-                            // some codes we don't care about, for instance
-                            // 50021 -- discord won't let us delete system messages
-                            50021 => {}
-                            50034 => {
-                                info!(
-                                    "We received 50034, resorting to chompy_delete_messages loop."
-                                );
-                                let _ = self.chompy_delete_messages(chunk.to_vec()).await;
-                            }
-
-                            _ => {
-                                if let Some(channel) = self.log_channel {
-                                    let _ = send_message_to_channel(
-                                        self.http.clone(),
-                                        self.guild_id,
-                                        channel,
-                                        format!("Unable to delete messages: {:#?}", e),
-                                    )
-                                    .await;
-                                }
-                            }
-                        }
+        for chunk in messages.chunks(100) {
+            if self.stopping() {
+                return;
+            }
+            if let Some(archiver) = &archiver {
+                if let Err(e) = archiver.archive(self.channel_id, run_started, chunk).await {
+                    error!("Failed to archive messages, not deleting them: {e:#}");
+                    run.errors += 1;
+                    self.report(format!(
+                        "Stopped sweeping {}: couldn't archive messages: {:#}",
+                        self.channel_id, e
+                    ))
+                    .await;
+                    return;
+                }
+            }
+
+            let (old, chunk): (Vec<&Message>, Vec<&Message>) = chunk
+                .iter()
+                .partition(|m| m.timestamp.deref() < &bulk_cutoff);
+            self.queue_old(old.iter().filter_map(|m| pending.remove(&m.id)).collect());
+            let chunk: Vec<MessageId> = chunk.iter().map(|m| m.id).collect();
+            if chunk.is_empty() {
+                continue;
+            }
+            if !self.delete_chunk(chunk, &mut pending, run).await {
+                return;
+            }
+        }
+    }
+
+    /// Post a digest of the messages about to be deleted to the digest channel, and protect it
+    /// from sweeps.  The sweep goes ahead without one if the model can't be reached.
+    async fn post_digest(
+        &self,
+        digest_channel: ChannelId,
+        messages: &[(Message, String)],
+        run: &mut SweepRun,
+    ) {
+        // the stream reads newest first, a transcript reads oldest first.
+        let messages: Vec<&Message> = messages.iter().rev().map(|(m, _)| m).collect();
+        let summary = match write_digest(&OllamaApi::new(), &messages).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                run.errors += 1;
+                error!("Couldn't write a digest of {}: {e:#}", self.channel_id);
+                self.report(format!(
+                    "Couldn't write a digest of <#{}>: {:#}",
+                    self.channel_id, e
+                ))
+                .await;
+                return;
+            }
+        };
+        let (first, last) = (
+            messages[0].timestamp,
+            messages[messages.len() - 1].timestamp,
+        );
+        let digest = format!(
+            "**Digest of <#{}>**, <t:{}:f> to <t:{}:f>\n{}",
+            self.channel_id,
+            first.unix_timestamp(),
+            last.unix_timestamp(),
+            summary.trim()
+        );
+        for part in split_for_discord(&digest) {
+            match self.store.say(digest_channel, part).await {
+                Ok(message_id) => {
+                    if let Err(e) = self.db.protect(digest_channel, message_id, DIGEST_KIND) {
+                        error!("Couldn't protect digest in {}: {e:#}", digest_channel);
                     }
-                } else {
-                    if let Some(channel) = self.log_channel {
-                        let _ = send_message_to_channel(
-                            self.http.clone(),
-                            self.guild_id,
-                            channel,
-                            format!("Unable to delete messages: {:#?}", e),
-                        )
-                        .await;
+                }
+                Err(e) => {
+                    run.errors += 1;
+                    self.report_error("post a digest", &e).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Bulk delete a chunk of messages, auditing the ones that go.  When discord refuses the chunk
+    /// because some of it is too old, `chompy_delete_messages` whittles it down and whatever is
+    /// left is queued for `delete_old_messages`.  When it's refused because of a message nobody can
+    /// delete, the chunk is deleted one message at a time around it.  Returns whether the sweep can
+    /// carry on with the next chunk.
+    async fn delete_chunk(
+        &mut self,
+        chunk: Vec<MessageId>,
+        pending: &mut HashMap<MessageId, Deletion>,
+        run: &mut SweepRun,
+    ) -> bool {
+        debug!("Issuing chunk delete for {} messages.", chunk.len());
+        match self.store.delete_messages(self.channel_id, &chunk).await {
+            Ok(()) => {
+                run.deleted += chunk.len() as u32;
+                self.audit(chunk.iter().filter_map(|id| pending.remove(id)).collect());
+                true
+            }
+            Err(DiscordError::TooOldToBulkDelete) => {
+                info!("Chunk has messages too old to bulk delete, resorting to chompy_delete_messages loop.");
+                let total = chunk.len() as u32;
+                match self.chompy_delete_messages(chunk.clone()).await {
+                    Ok(leftover) => {
+                        run.deleted += total - leftover.len() as u32;
+                        self.queue_old(
+                            leftover
+                                .iter()
+                                .filter_map(|id| pending.remove(id))
+                                .collect(),
+                        );
+                        self.audit(chunk.iter().filter_map(|id| pending.remove(id)).collect());
+                        true
+                    }
+                    Err(e) => {
+                        run.errors += 1;
+                        self.report_error("delete messages", &e).await;
+                        false
                     }
                 }
+            }
+            // one system message, or one that's already gone, fails the whole chunk.
+            Err(e) if e.policy() == ErrorPolicy::Skip => {
+                debug!("Couldn't bulk delete chunk, deleting it one message at a time: {e}");
+                self.delete_each(chunk, pending, run).await
+            }
+            Err(e) => {
+                run.errors += 1;
+                self.report_error("delete messages", &e).await;
+                false
+            }
+        }
+    }
 
-                return;
+    /// Delete messages one at a time, passing over the ones that can't be deleted.  Returns whether
+    /// the sweep can carry on.
+    async fn delete_each(
+        &mut self,
+        chunk: Vec<MessageId>,
+        pending: &mut HashMap<MessageId, Deletion>,
+        run: &mut SweepRun,
+    ) -> bool {
+        let mut deleted = vec![];
+        let mut carry_on = true;
+        for message_id in chunk {
+            match self.store.delete_message(self.channel_id, message_id).await {
+                Ok(()) => {
+                    run.deleted += 1;
+                    deleted.extend(pending.remove(&message_id));
+                }
+                Err(e) if e.policy() == ErrorPolicy::Skip => {
+                    debug!(%message_id, "Couldn't delete message, passing over it: {e}");
+                    pending.remove(&message_id);
+                }
+                Err(e) => {
+                    run.errors += 1;
+                    self.report_error("delete messages", &e).await;
+                    carry_on = false;
+                    break;
+                }
             }
         }
+        self.audit(deleted);
+        carry_on
+    }
 
-        self.stats.runs += 1;
-        self.stats.last_run = success_count;
-        self.stats.all_runs += success_count;
-        self.stats_tx
-            .send(self.stats.clone())
-            .expect("failed to update stats");
+    /// A token that stops this sweeper's runs after the chunk they're on.
+    pub(crate) fn stop_token(&self) -> CancellationToken {
+        self.stop.clone()
+    }
+
+    /// Whether to stop deleting because the bot is shutting down or the sweeper was stopped.
+    /// Checked between chunks, so whatever is left waits for the next run.
+    fn stopping(&self) -> bool {
+        let stopping = self.stop.is_cancelled();
+        if stopping && Shutdown::get().is_shutting_down() {
+            info!(
+                "Shutting down, leaving the rest of {} for next run.",
+                self.channel_id
+            );
+        } else if stopping {
+            info!("Sweeper for {} was stopped mid-sweep.", self.channel_id);
+        }
+        stopping
+    }
+
+    /// Queue messages for `delete_old_messages`, remembering them in the database as well.
+    fn queue_old(&mut self, deletions: Vec<Deletion>) {
+        if deletions.is_empty() {
+            return;
+        }
+        if let Err(e) = self.db.queue_old_messages(&deletions) {
+            error!(
+                "Couldn't save {} queued old messages: {e:#}",
+                deletions.len()
+            );
+        }
+        self.old_messages
+            .extend(deletions.into_iter().map(|d| (d.message_id, d)));
+    }
+
+    /// Take queued old messages that this run's read of the channel didn't find still expired off
+    /// the queue: they've been deleted, pinned or kept since, or a longer max age now applies.
+    fn recheck_old(&mut self, still_expired: HashSet<MessageId>) {
+        let stale: Vec<MessageId> = self
+            .old_messages
+            .keys()
+            .filter(|id| !still_expired.contains(id))
+            .copied()
+            .collect();
+        for message_id in stale {
+            debug!(%message_id, "Queued old message isn't expired any more, unqueueing it.");
+            self.old_messages.remove(&message_id);
+            self.unqueue_old(message_id);
+        }
+        self.old_checked = true;
+    }
+
+    /// Take a message off the queue of old messages for good.
+    fn unqueue_old(&self, message_id: MessageId) {
+        if let Err(e) = self.db.unqueue_old_message(message_id) {
+            error!(%message_id, "Couldn't remove queued old message: {e:#}");
+        }
+    }
+
+    /// Add messages that were just deleted to the audit log.
+    fn audit(&self, deletions: Vec<Deletion>) {
+        if deletions.is_empty() {
+            return;
+        }
+        if let Err(e) = self
+            .db
+            .record_deletions(self.run_id, Utc::now(), &deletions)
+        {
+            error!("Couldn't record {} deletions: {e:#}", deletions.len());
+        }
+    }
+
+    /// Log a discord error and tell the log channel about it, with what to do about it when the
+    /// bot's permissions are to blame.
+    async fn report_error(&self, action: &str, error: &DiscordError) {
+        error!("Couldn't {action} in {}: {error}", self.channel_id);
+        let message = match error.is_misconfiguration() {
+            true => format!(
+                "Couldn't {action} in <#{}>: {error}. Give the bot View Channel, Read Message \
+                 History and Manage Messages there (and Manage Threads for its threads).",
+                self.channel_id
+            ),
+            false => format!("Couldn't {action} in <#{}>: {error}.", self.channel_id),
+        };
+        self.report(message).await;
     }
 
-    /// Returns a stream of messages for the sweeper's channel. The stream takes care of paginating
-    /// the response.
-    fn message_stream(&self) -> Pin<Box<impl Stream<Item = serenity::Result<Message>> + '_>> {
+    /// Send a message to the log channel, if there is one.
+    async fn report(&self, message: String) {
+        if let Some(channel) = self.log_channel {
+            if let Err(e) = self.store.say(channel, message).await {
+                error!("Couldn't report to the log channel: {e}");
+            }
+        }
+    }
+
+    /// Returns a stream of the sweeper's channel's messages sent before `cutoff`, newest first.
+    /// Paging starts from the cutoff's snowflake and walks back through history, so messages that
+    /// haven't expired are never fetched.
+    fn message_stream(
+        &self,
+        cutoff: DateTime<Utc>,
+    ) -> Pin<Box<impl Stream<Item = Result<Message, DiscordError>> + '_>> {
         Box::pin(try_stream! {
-            let mut cursor = MessageId(0);
+            let mut cursor = snowflake_at(cutoff);
             while let messages = self.load_messages(cursor).await? {
                 if messages.is_empty() {
                     break;
@@ -252,21 +928,93 @@ impl Sweeper {
         })
     }
 
-    /// Load a page of messages from discord and sort them by timestamp.
-    async fn load_messages(&self, cursor: impl Into<MessageId>) -> serenity::Result<Vec<Message>> {
+    /// Load a page of messages sent before `cursor` from discord, newest first.
+    async fn load_messages(&self, cursor: MessageId) -> Result<Vec<Message>, DiscordError> {
         let mut messages = self
-            .channel_id
-            .messages(&self.http, |b| b.after(cursor.into()).limit(25))
+            .store
+            .messages(self.channel_id, Some(cursor), MESSAGE_PAGE_SIZE)
             .await?;
 
-        messages.sort_by_key(|m| m.timestamp);
+        messages.sort_by_key(|m| std::cmp::Reverse(m.id));
         Ok(messages)
     }
 
+    /// Delete queued messages that are too old for bulk delete through the single message endpoint,
+    /// spending at most the channel's per-run budget and pausing between each delete.  A dry run
+    /// leaves the queue alone, even if a live run filled it, and so does a run that couldn't check
+    /// the queue against the channel.
+    async fn delete_old_messages(&mut self, run: &mut SweepRun) {
+        let policy = self.config.old_messages.clone();
+        if self.dry_run || !policy.delete || self.old_messages.is_empty() {
+            self.stats.old_pending = self.old_messages.len() as u32;
+            return;
+        }
+        if !self.old_checked {
+            info!(
+                pending = self.old_messages.len(),
+                "Queued old messages weren't checked this run, leaving them for the next."
+            );
+            self.stats.old_pending = self.old_messages.len() as u32;
+            return;
+        }
+
+        info!(
+            pending = self.old_messages.len(),
+            budget = policy.per_run,
+            "Deleting messages too old for bulk delete one at a time."
+        );
+        let interval: core::time::Duration = policy.interval.into();
+        let mut deleted = 0;
+        while deleted < policy.per_run && !self.stopping() {
+            let Some((message_id, deletion)) = self.old_messages.pop_first() else {
+                break;
+            };
+            match self.store.delete_message(self.channel_id, message_id).await {
+                Ok(()) => {
+                    deleted += 1;
+                    self.unqueue_old(message_id);
+                    self.audit(vec![deletion]);
+                }
+                // already gone, or a system message nobody can delete.
+                Err(e) if e.policy() == ErrorPolicy::Skip => {
+                    self.unqueue_old(message_id);
+                    continue;
+                }
+                Err(e) => {
+                    run.errors += 1;
+                    self.old_messages.insert(message_id, deletion);
+                    match e.is_misconfiguration() {
+                        true => self.report_error("delete old messages", &e).await,
+                        false => warn!(
+                            %message_id,
+                            "Couldn't delete old message, will retry next run: {e}"
+                        ),
+                    }
+                    break;
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+
+        run.deleted += deleted;
+        self.stats.old_deleted += deleted;
+        self.stats.old_pending = self.old_messages.len() as u32;
+        info!(
+            deleted,
+            pending = self.stats.old_pending,
+            "Finished deleting old messages."
+        );
+    }
+
     /// Delete a chunk of messages using bulk delete, continuously splitting the chunk into two
-    /// chunks to whittle down the erroneous message.
-    async fn chompy_delete_messages(&self, chunks: Vec<MessageId>) -> Result<(), anyhow::Error> {
+    /// chunks to whittle down the erroneous message.  Returns the messages that still couldn't be
+    /// bulk deleted, so they can be deleted one at a time instead.
+    async fn chompy_delete_messages(
+        &self,
+        chunks: Vec<MessageId>,
+    ) -> Result<Vec<MessageId>, DiscordError> {
         let mut pending = vec![chunks];
+        let mut leftover = vec![];
 
         while let Some(chunk) = pending.pop() {
             info!("Deleting chunk of {} messages.", chunk.len());
@@ -274,58 +1022,498 @@ impl Sweeper {
                 continue;
             }
 
-            if let Err(e) = self
-                .channel_id
-                .delete_messages(self.http.clone(), chunk.clone())
-                .await
-            {
-                if let serenity::Error::Http(boxed) = &e {
-                    if let HttpError::UnsuccessfulRequest(er) = boxed.deref() {
-                        match er.error.code {
-                            // Discord error 50034 usually means at least one message in the
-                            // bulk-delete request is too old to be deleted this way.
-                            50034 => {
-                                if chunk.len() == 1 {
-                                    debug!(
-                                        message_id = %chunk[0],
-                                        "Skipping message that cannot be bulk deleted."
-                                    );
-                                    continue;
-                                }
-
-                                let mid = chunk.len() / 2;
-                                let left = chunk[..mid].to_vec();
-                                let right = chunk[mid..].to_vec();
-
-                                pending.push(left);
-                                pending.push(right);
-                                continue;
-                            }
-                            _ => {
-                                return Err(e.into());
-                            }
-                        }
+            match self.store.delete_messages(self.channel_id, &chunk).await {
+                Ok(()) => {}
+                // at least one message in the chunk is too old to be deleted this way.
+                Err(DiscordError::TooOldToBulkDelete) => {
+                    if chunk.len() == 1 {
+                        debug!(
+                            message_id = %chunk[0],
+                            "Message cannot be bulk deleted, queueing it for single delete."
+                        );
+                        leftover.push(chunk[0]);
+                        continue;
                     }
-                }
 
-                return Err(e.into());
+                    let mid = chunk.len() / 2;
+                    let left = chunk[..mid].to_vec();
+                    let right = chunk[mid..].to_vec();
+
+                    pending.push(left);
+                    pending.push(right);
+                }
+                Err(e) => return Err(e),
             }
         }
 
-        Ok(())
+        Ok(leftover)
+    }
+}
+
+/// A channel's queue of old messages, as the database has it.  Starts empty if it can't be read,
+/// and sweeps find the messages again.
+fn load_old_messages(db: &Database, channel_id: ChannelId) -> BTreeMap<MessageId, Deletion> {
+    match db.old_messages(channel_id) {
+        Ok(deletions) => deletions.into_iter().map(|d| (d.message_id, d)).collect(),
+        Err(e) => {
+            error!(
+                "Couldn't load queued old messages for {}: {e:#}",
+                channel_id
+            );
+            BTreeMap::new()
+        }
     }
 }
-/// send a message to a specified channel.
-async fn send_message_to_channel(
-    http: Arc<Http>,
-    _guild: GuildId,
-    channel: ChannelId,
-    message: String,
-) -> anyhow::Result<()> {
-    let formatted_message = MessageBuilder::new().push(message).build();
-    if let Err(e) = channel.say(&http, formatted_message).await {
-        error!("Couldn't use channel.say in eventhandler: {e}")
-    };
-
-    Ok(())
+
+/// The smallest snowflake that could have been created at `at`, so every message sent before then
+/// has a lower id.
+pub(crate) fn snowflake_at(at: DateTime<Utc>) -> MessageId {
+    let millis = (at.timestamp_millis() - DISCORD_EPOCH_MILLIS).max(0);
+    MessageId((millis as u64) << 22)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::rules::{KeepReaction, Rule};
+    use crate::models::store::fake::FakeStore;
+    use duration_string::DurationString;
+    use serenity::model::id::RoleId;
+
+    const GUILD: GuildId = GuildId(1);
+    const CHANNEL: ChannelId = ChannelId(2);
+    const LOG_CHANNEL: ChannelId = ChannelId(3);
+
+    fn sweeper(store: &Arc<FakeStore>, dry_run: bool) -> (Sweeper, watch::Receiver<Stats>) {
+        let max_age: DurationString = "1d".to_string().try_into().unwrap();
+        let mut channel = ChannelConfig::new(CHANNEL, max_age, dry_run);
+        channel.old_messages.interval = core::time::Duration::ZERO.into();
+        let db = Arc::new(Database::open(":memory:").unwrap());
+        let (mut sweeper, rx) = Sweeper::new(store.clone(), GUILD, &channel, false, db);
+        sweeper.log_channel = Some(LOG_CHANNEL);
+        (sweeper, rx)
+    }
+
+    /// Post `count` messages, one a second going back from `newest`.
+    fn post_many(store: &FakeStore, count: i64, newest: DateTime<Utc>) -> Vec<MessageId> {
+        (0..count)
+            .map(|i| store.post(CHANNEL, 10, newest - Duration::seconds(i), 0, false))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sweep_pages_back_from_the_cutoff() {
+        let store = Arc::new(FakeStore::default());
+        let now = Utc::now();
+        let expired = post_many(&store, 250, now - Duration::days(2));
+        let recent = post_many(&store, 30, now - Duration::hours(1));
+        let (mut sweeper, _rx) = sweeper(&store, false);
+
+        sweeper.sweep_messages().await;
+
+        let mut remaining = store.remaining(CHANNEL);
+        remaining.sort();
+        let mut expected = recent;
+        expected.sort();
+        assert_eq!(remaining, expected);
+        assert_eq!(sweeper.stats.last_run, expired.len() as u32);
+        let state = store.state();
+        // three full or partial pages of expired messages and an empty one to finish, without
+        // ever reading the recent messages.
+        assert_eq!(state.pages_read, 4);
+        assert!(state.bulk_deletes.iter().all(|chunk| chunk.len() <= 100));
+    }
+
+    #[tokio::test]
+    async fn sweep_keeps_pinned_messages() {
+        let store = Arc::new(FakeStore::default());
+        let at = Utc::now() - Duration::days(2);
+        post_many(&store, 10, at);
+        let pinned = store.post(CHANNEL, 10, at - Duration::hours(1), 0, true);
+        let (mut sweeper, _rx) = sweeper(&store, false);
+
+        sweeper.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL), vec![pinned]);
+        assert_eq!(sweeper.stats.last_run, 10);
+    }
+
+    #[tokio::test]
+    async fn sweep_deletes_old_messages_one_at_a_time() {
+        let store = Arc::new(FakeStore::default());
+        let now = Utc::now();
+        let old = post_many(&store, 5, now - Duration::days(20));
+        post_many(&store, 5, now - Duration::days(2));
+        let (mut sweeper, _rx) = sweeper(&store, false);
+
+        sweeper.sweep_messages().await;
+
+        assert!(store.remaining(CHANNEL).is_empty());
+        assert_eq!(sweeper.stats.last_run, 10);
+        assert_eq!(sweeper.stats.old_deleted, 5);
+        let state = store.state();
+        assert!(state
+            .bulk_deletes
+            .iter()
+            .flatten()
+            .all(|id| !old.contains(id)));
+    }
+
+    #[tokio::test]
+    async fn old_message_queue_outlasts_the_sweeper() {
+        let store = Arc::new(FakeStore::default());
+        post_many(&store, 5, Utc::now() - Duration::days(20));
+        let (mut sweeper, _rx) = sweeper(&store, false);
+        sweeper.config.old_messages.per_run = 2;
+
+        sweeper.sweep_messages().await;
+        assert_eq!(store.remaining(CHANNEL).len(), 3);
+
+        // restarts rebuild the sweeper, and so do set-age, pause and resume.
+        let config = sweeper.config.clone();
+        let (rebuilt, _rx) = Sweeper::new(store.clone(), GUILD, &config, false, sweeper.db.clone());
+        assert_eq!(rebuilt.stats.old_pending, 3);
+        assert_eq!(
+            rebuilt.old_messages.keys().collect::<Vec<_>>(),
+            sweeper.old_messages.keys().collect::<Vec<_>>()
+        );
+    }
+
+    /// A sweeper whose queue holds the channel's five 20 day old messages, with none deleted yet.
+    async fn queued_sweeper(store: &Arc<FakeStore>) -> (Sweeper, Vec<MessageId>) {
+        let ids = post_many(store, 5, Utc::now() - Duration::days(20));
+        let (mut sweeper, _rx) = sweeper(store, false);
+        sweeper.config.old_messages.per_run = 0;
+        sweeper.sweep_messages().await;
+        assert_eq!(sweeper.old_messages.len(), 5);
+        sweeper.config.old_messages.per_run = 500;
+        (sweeper, ids)
+    }
+
+    #[tokio::test]
+    async fn queued_messages_pinned_since_are_kept() {
+        let store = Arc::new(FakeStore::default());
+        let (mut sweeper, ids) = queued_sweeper(&store).await;
+        let pinned = ids[2];
+        if let Some(m) = store
+            .state()
+            .messages
+            .get_mut(&CHANNEL)
+            .unwrap()
+            .get_mut(&pinned)
+        {
+            m.pinned = true;
+        }
+
+        sweeper.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL), vec![pinned]);
+        assert!(sweeper.old_messages.is_empty());
+        assert!(sweeper.db.old_messages(CHANNEL).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_kept_under_a_longer_max_age() {
+        let store = Arc::new(FakeStore::default());
+        let (sweeper, _ids) = queued_sweeper(&store).await;
+
+        // set-age rebuilds the sweeper with the saved queue.
+        let mut config = sweeper.config.clone();
+        config.max_message_age = "30d".to_string().try_into().unwrap();
+        let (mut rebuilt, _rx) =
+            Sweeper::new(store.clone(), GUILD, &config, false, sweeper.db.clone());
+        rebuilt.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL).len(), 5);
+        assert_eq!(rebuilt.stats.old_pending, 0);
+    }
+
+    #[tokio::test]
+    async fn purge_deletes_only_the_members_messages() {
+        let store = Arc::new(FakeStore::default());
+        let (sweeper, queued) = queued_sweeper(&store).await;
+        let now = Utc::now();
+        let mut purged = vec![
+            store.post(CHANNEL, 11, now - Duration::days(20), 1, false),
+            store.post(CHANNEL, 11, now - Duration::hours(1), 0, false),
+        ];
+        purged.extend((0..3).map(|i| store.post(CHANNEL, 11, now - Duration::days(2), i, false)));
+        let mut purger = Sweeper::purger(
+            store.clone(),
+            GUILD,
+            &sweeper.config,
+            false,
+            sweeper.db.clone(),
+        );
+        let mut report = PurgeReport::default();
+
+        purger
+            .purge_author(UserId(11), "forget-me", &mut report)
+            .await;
+
+        assert_eq!(
+            store.remaining(CHANNEL),
+            queued.iter().rev().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(report.found, 5);
+        assert_eq!(report.run.deleted, 5);
+        assert_eq!(sweeper.db.old_messages(CHANNEL).unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn dry_run_leaves_the_old_message_queue_alone() {
+        let store = Arc::new(FakeStore::default());
+        post_many(&store, 5, Utc::now() - Duration::days(20));
+        let (mut live, _rx) = sweeper(&store, false);
+        live.config.old_messages.delete = false;
+        live.sweep_messages().await;
+        assert_eq!(live.db.old_messages(CHANNEL).unwrap().len(), 5);
+
+        // the channel is switched to dry run with its queue still saved.
+        let mut config = live.config.clone();
+        config.old_messages.delete = true;
+        let (mut dry, _rx) = Sweeper::new(store.clone(), GUILD, &config, true, live.db.clone());
+        dry.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL).len(), 5);
+        assert_eq!(dry.stats.old_pending, 5);
+    }
+
+    #[tokio::test]
+    async fn sweep_outlives_its_stats_receiver() {
+        let store = Arc::new(FakeStore::default());
+        post_many(&store, 10, Utc::now() - Duration::days(2));
+        // /sweeper remove drops the receiver while the sweep is still running.
+        let (mut sweeper, rx) = sweeper(&store, false);
+        drop(rx);
+
+        sweeper.sweep_messages().await;
+
+        assert!(store.remaining(CHANNEL).is_empty());
+        assert_eq!(sweeper.stats.last_run, 10);
+    }
+
+    #[tokio::test]
+    async fn dry_run_deletes_nothing_and_reports() {
+        let store = Arc::new(FakeStore::default());
+        post_many(&store, 10, Utc::now() - Duration::days(2));
+        let (mut sweeper, _rx) = sweeper(&store, true);
+
+        sweeper.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL).len(), 10);
+        let state = store.state();
+        assert!(state.bulk_deletes.is_empty());
+        assert_eq!(state.said.len(), 1);
+        assert_eq!(state.said[0].0, LOG_CHANNEL);
+        assert!(state.said[0].1.contains("10 messages would be deleted"));
+    }
+
+    #[tokio::test]
+    async fn missing_permissions_are_reported_as_misconfiguration() {
+        let store = Arc::new(FakeStore::default());
+        post_many(&store, 10, Utc::now() - Duration::days(2));
+        store
+            .state()
+            .bulk_errors
+            .push_back(DiscordError::MissingPermissions);
+        let (mut sweeper, _rx) = sweeper(&store, false);
+
+        sweeper.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL).len(), 10);
+        let state = store.state();
+        assert_eq!(state.said.len(), 1);
+        assert!(state.said[0].1.contains("Manage Messages"));
+    }
+
+    #[tokio::test]
+    async fn undeletable_messages_are_passed_over() {
+        let store = Arc::new(FakeStore::default());
+        let ids = post_many(&store, 250, Utc::now() - Duration::days(2));
+        // a system message in the first chunk fails its bulk delete.
+        store
+            .state()
+            .undeletable
+            .insert(ids[10], DiscordError::SystemMessage);
+        let (mut sweeper, _rx) = sweeper(&store, false);
+
+        sweeper.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL), vec![ids[10]]);
+        assert_eq!(sweeper.stats.last_run, 249);
+        assert!(store.state().said.is_empty());
+    }
+
+    #[tokio::test]
+    async fn archived_threads_are_reported_once() {
+        let store = Arc::new(FakeStore::default());
+        let ids = post_many(&store, 10, Utc::now() - Duration::days(2));
+        store.state().undeletable = ids
+            .iter()
+            .map(|id| (*id, DiscordError::ThreadArchived))
+            .collect();
+        let (mut sweeper, _rx) = sweeper(&store, false);
+        let mut run = SweepRun::default();
+        let mut dry_run = DryRunReport::new(GUILD, CHANNEL);
+
+        sweeper
+            .sweep_expired(Utc::now(), &mut run, &mut dry_run)
+            .await;
+
+        assert_eq!(store.remaining(CHANNEL).len(), 10);
+        assert_eq!(run.errors, 1);
+        let state = store.state();
+        assert_eq!(state.bulk_deletes.len(), 1);
+        assert_eq!(state.said.len(), 1);
+        assert!(state.said[0].1.contains("archived"));
+    }
+
+    #[tokio::test]
+    async fn role_rule_keeps_messages_when_roles_cant_be_looked_up() {
+        let store = Arc::new(FakeStore::default());
+        let at = Utc::now() - Duration::days(2);
+        let moderator = store.post(CHANNEL, 20, at, 0, false);
+        let unknown = store.post(CHANNEL, 21, at, 1, false);
+        store.post(CHANNEL, 22, at, 2, false);
+        // a member who left has no roles.
+        store.post(CHANNEL, 23, at, 3, false);
+        {
+            let mut state = store.state();
+            state.roles.insert(UserId(20), Ok(vec![RoleId(5)]));
+            state.roles.insert(
+                UserId(21),
+                Err(DiscordError::Unavailable(
+                    "discord returned 502".to_string(),
+                )),
+            );
+            state.roles.insert(UserId(22), Ok(vec![RoleId(6)]));
+        }
+        let (mut sweeper, _rx) = sweeper(&store, false);
+        sweeper.config.rules = vec![Rule::Roles { ids: vec![5] }];
+
+        sweeper.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL), vec![moderator, unknown]);
+    }
+
+    fn keep(min_count: u64, role: Option<u64>, extension: Option<&str>) -> KeepReaction {
+        KeepReaction {
+            emoji: "📌".to_string(),
+            min_count,
+            role,
+            extension: extension.map(|e| e.to_string().try_into().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_reaction_needs_enough_reactions() {
+        let store = Arc::new(FakeStore::default());
+        let at = Utc::now() - Duration::days(2);
+        let once = store.post(CHANNEL, 10, at, 0, false);
+        let twice = store.post(CHANNEL, 10, at, 1, false);
+        let other_emoji = store.post(CHANNEL, 10, at, 2, false);
+        store.react(CHANNEL, once, "📌", &[20]);
+        store.react(CHANNEL, twice, "📌", &[20, 21]);
+        store.react(CHANNEL, other_emoji, "👍", &[20, 21]);
+        let (mut sweeper, _rx) = sweeper(&store, false);
+        sweeper.config.keep = Some(keep(2, None, None));
+
+        sweeper.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL), vec![twice]);
+    }
+
+    #[tokio::test]
+    async fn keep_reaction_only_counts_members_with_the_role() {
+        let store = Arc::new(FakeStore::default());
+        let at = Utc::now() - Duration::days(2);
+        let one_regular = store.post(CHANNEL, 10, at, 0, false);
+        let two_regulars = store.post(CHANNEL, 10, at, 1, false);
+        store.react(CHANNEL, one_regular, "📌", &[20, 22]);
+        store.react(CHANNEL, two_regulars, "📌", &[20, 21, 22]);
+        {
+            let mut state = store.state();
+            state.roles.insert(UserId(20), Ok(vec![RoleId(5)]));
+            state
+                .roles
+                .insert(UserId(21), Ok(vec![RoleId(5), RoleId(6)]));
+            state.roles.insert(UserId(22), Ok(vec![RoleId(6)]));
+        }
+        let (mut sweeper, _rx) = sweeper(&store, false);
+        sweeper.config.keep = Some(keep(2, Some(5), None));
+
+        sweeper.sweep_messages().await;
+
+        assert_eq!(store.remaining(CHANNEL), vec![two_regulars]);
+    }
+
+    #[tokio::test]
+    async fn keep_reaction_keeps_a_message_until_its_extension_runs_out() {
+        let store = Arc::new(FakeStore::default());
+        let now = Utc::now();
+        // the channel's max age is a day, and the extension another day.
+        let lapsed = store.post(CHANNEL, 10, now - Duration::hours(50), 0, false);
+        let extended = store.post(CHANNEL, 10, now - Duration::hours(40), 0, false);
+        let unkept = store.post(CHANNEL, 10, now - Duration::hours(40), 1, false);
+        store.react(CHANNEL, lapsed, "📌", &[20]);
+        store.react(CHANNEL, extended, "📌", &[20]);
+        let (mut sweeper, _rx) = sweeper(&store, false);
+        sweeper.config.keep = Some(keep(1, None, Some("1d")));
+
+        sweeper.sweep_messages().await;
+
+        let remaining = store.remaining(CHANNEL);
+        assert_eq!(remaining, vec![extended]);
+        assert!(!remaining.contains(&unkept));
+    }
+
+    #[tokio::test]
+    async fn chompy_splits_around_messages_too_old_for_bulk_delete() {
+        let store = Arc::new(FakeStore::default());
+        let now = Utc::now();
+        let mut ids = post_many(&store, 6, now - Duration::days(2));
+        ids.extend(post_many(&store, 2, now - Duration::days(20)));
+        let (sweeper, _rx) = sweeper(&store, false);
+
+        let leftover = sweeper.chompy_delete_messages(ids.clone()).await.unwrap();
+
+        // a lone message goes through the single delete endpoint, which takes messages of any age.
+        assert!(leftover.is_empty());
+        assert!(store.remaining(CHANNEL).is_empty());
+        let state = store.state();
+        assert_eq!(state.bulk_deletes[0], ids);
+        assert!(state.bulk_deletes.iter().all(|chunk| chunk.len() > 1));
+    }
+
+    #[tokio::test]
+    async fn chompy_stops_on_other_errors() {
+        let store = Arc::new(FakeStore::default());
+        let now = Utc::now();
+        let mut ids = post_many(&store, 6, now - Duration::days(2));
+        ids.extend(post_many(&store, 2, now - Duration::days(20)));
+        store.state().bulk_errors.extend([
+            DiscordError::TooOldToBulkDelete,
+            DiscordError::MissingAccess,
+        ]);
+        let (sweeper, _rx) = sweeper(&store, false);
+
+        let result = sweeper.chompy_delete_messages(ids).await;
+
+        assert_eq!(result, Err(DiscordError::MissingAccess));
+        assert_eq!(store.remaining(CHANNEL).len(), 8);
+        assert_eq!(store.state().bulk_deletes.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stream_reports_read_errors() {
+        let store = Arc::new(FakeStore::default());
+        let (mut sweeper, _rx) = sweeper(&store, false);
+        // the fake doesn't know the channel, so reading it fails.
+        sweeper.sweep_messages().await;
+
+        let state = store.state();
+        assert_eq!(state.said.len(), 1);
+        assert!(state.said[0].1.contains("read messages"));
+    }
 }
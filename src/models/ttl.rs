@@ -0,0 +1,631 @@
+use crate::models::config::{to_chrono, ChannelConfig};
+use crate::models::db::{Database, Deletion, SweepRun};
+use crate::models::discord_error::{DiscordError, ErrorPolicy};
+use crate::models::shutdown::Shutdown;
+use crate::models::store::MessageStore;
+use crate::models::sweeper::{Stats, BULK_DELETE_MAX_AGE_DAYS, BULK_DELETE_SLACK_MINUTES};
+use crate::CONNECTED;
+use chrono::{DateTime, Duration, Utc};
+use futures::{FutureExt, StreamExt};
+use serenity::model::id::{ChannelId, MessageId, UserId};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::time::delay_queue::{DelayQueue, Key};
+
+/// How long a TTL channel's deletions are rolled into one sweep history record.
+const RUN_RECORD_HOURS: i64 = 1;
+/// How long a deletion that failed waits before it's tried again, doubled after each failure.
+const RETRY_BACKOFF_SECS: u64 = 30;
+/// The longest a failed deletion waits, so deletions held up by missing permissions go through
+/// soon after they're fixed.
+const MAX_RETRY_BACKOFF_SECS: u64 = 60 * 60;
+
+/// What the TTL reaper is told about.  The handler sends every message it sees; the manager sends
+/// channels as they start and stop.
+pub(crate) enum TtlEvent {
+    Watch {
+        channel: Box<ChannelConfig>,
+        dry_run: bool,
+        stats_tx: watch::Sender<Stats>,
+    },
+    Unwatch(ChannelId),
+    Message {
+        channel_id: ChannelId,
+        message_id: MessageId,
+        author_id: UserId,
+        timestamp: DateTime<Utc>,
+    },
+}
+
+/// Deletes messages in TTL channels as they expire.  Each message is put on a delay queue when it
+/// arrives, so nothing has to page through the channel to find what's expired.  A channel's queue
+/// is rebuilt from its history when it's watched, which covers messages sent while the bot was
+/// down.
+pub(crate) struct TtlReaper {
+    store: Arc<dyn MessageStore>,
+    db: Arc<Database>,
+    rx: mpsc::UnboundedReceiver<TtlEvent>,
+    /// Messages found by reading channels' history, fed in alongside the handler's.
+    rebuilt_tx: mpsc::UnboundedSender<TtlEvent>,
+    rebuilt_rx: mpsc::UnboundedReceiver<TtlEvent>,
+    channels: HashMap<ChannelId, TtlChannel>,
+    queue: DelayQueue<Queued>,
+    keys: HashMap<MessageId, (ChannelId, Key)>,
+}
+
+/// A message waiting on the queue, and how many times deleting it has failed.
+struct Queued {
+    deletion: Deletion,
+    failures: u32,
+}
+
+struct TtlChannel {
+    ttl: Duration,
+    /// What the audit log says deleted a message.
+    reason: String,
+    dry_run: bool,
+    delete_old: bool,
+    stats: Stats,
+    stats_tx: watch::Sender<Stats>,
+    /// The sweep history record the channel's deletions are currently added to.
+    run_id: Option<i64>,
+    run_started: DateTime<Utc>,
+    run: SweepRun,
+    /// Reads the channel's history into the queue when it's first watched.
+    rebuild: JoinHandle<()>,
+}
+
+impl TtlReaper {
+    pub(crate) fn new(
+        store: Arc<dyn MessageStore>,
+        db: Arc<Database>,
+    ) -> (Self, mpsc::UnboundedSender<TtlEvent>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (rebuilt_tx, rebuilt_rx) = mpsc::unbounded_channel();
+        (
+            TtlReaper {
+                store,
+                db,
+                rx,
+                rebuilt_tx,
+                rebuilt_rx,
+                channels: HashMap::new(),
+                queue: DelayQueue::new(),
+                keys: HashMap::new(),
+            },
+            tx,
+        )
+    }
+
+    pub(crate) async fn run(mut self) {
+        while !CONNECTED.initialized() {
+            tokio::time::sleep(core::time::Duration::from_secs(1)).await;
+        }
+
+        info!("Bot ready, starting TTL reaper.");
+        loop {
+            tokio::select! {
+                event = self.rx.recv() => match event {
+                    Some(event) => self.handle(event).await,
+                    None => return,
+                },
+                Some(event) = self.rebuilt_rx.recv() => self.handle(event).await,
+                Some(expired) = self.queue.next(), if !self.queue.is_empty() => {
+                    // pick up everything else that's due too, so it goes out in one bulk delete.
+                    let mut due = vec![expired.into_inner()];
+                    while let Some(Some(expired)) = self.queue.next().now_or_never() {
+                        due.push(expired.into_inner());
+                    }
+                    self.reap(due).await;
+                }
+                _ = Shutdown::get().requested() => {
+                    info!("Shutting down, stopping TTL reaper.");
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, event: TtlEvent) {
+        match event {
+            TtlEvent::Watch {
+                channel,
+                dry_run,
+                stats_tx,
+            } => {
+                let channel_id = channel.channel_id();
+                let Some(ttl_string) = channel.ttl else {
+                    return;
+                };
+                // a long history takes a while to read, and other channels' messages keep expiring.
+                let store = self.store.clone();
+                let tx = self.rebuilt_tx.clone();
+                let rebuild = tokio::spawn(async move {
+                    tokio::select! {
+                        result = rebuild(store, channel_id, tx) => if let Err(e) = result {
+                            error!("Couldn't load history for TTL channel {}: {e}", channel_id);
+                        },
+                        _ = Shutdown::get().requested() => {}
+                    }
+                });
+                let previous = self.channels.insert(
+                    channel_id,
+                    TtlChannel {
+                        ttl: to_chrono(ttl_string),
+                        reason: format!("ttl({ttl_string})"),
+                        dry_run: dry_run || channel.dry_run,
+                        delete_old: channel.old_messages.delete,
+                        stats: Stats::new(channel_id),
+                        stats_tx,
+                        run_id: None,
+                        run_started: Utc::now(),
+                        run: SweepRun::default(),
+                        rebuild,
+                    },
+                );
+                if let Some(mut previous) = previous {
+                    previous.rebuild.abort();
+                    self.flush_run(channel_id, &mut previous);
+                }
+            }
+            TtlEvent::Unwatch(channel_id) => {
+                if let Some(mut channel) = self.channels.remove(&channel_id) {
+                    channel.rebuild.abort();
+                    self.flush_run(channel_id, &mut channel);
+                }
+                let queue = &mut self.queue;
+                self.keys.retain(|_, (channel, key)| {
+                    if *channel == channel_id {
+                        queue.remove(key);
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+            TtlEvent::Message {
+                channel_id,
+                message_id,
+                author_id,
+                timestamp,
+            } => {
+                if let Some(channel) = self.channels.get(&channel_id) {
+                    let deletion = Deletion {
+                        message_id,
+                        channel_id,
+                        author_id: Some(author_id),
+                        sent_at: timestamp,
+                        reason: channel.reason.clone(),
+                    };
+                    self.schedule(deletion, timestamp + channel.ttl);
+                }
+            }
+        }
+    }
+
+    fn schedule(&mut self, deletion: Deletion, expires: DateTime<Utc>) {
+        let delay = (expires - Utc::now()).to_std().unwrap_or_default();
+        match self.keys.get(&deletion.message_id) {
+            Some((_, key)) => self.queue.reset(key, delay),
+            None => self.enqueue(
+                Queued {
+                    deletion,
+                    failures: 0,
+                },
+                delay,
+            ),
+        }
+    }
+
+    fn enqueue(&mut self, queued: Queued, delay: core::time::Duration) {
+        let (message_id, channel_id) = (queued.deletion.message_id, queued.deletion.channel_id);
+        let key = self.queue.insert(queued, delay);
+        self.keys.insert(message_id, (channel_id, key));
+    }
+
+    /// Put a deletion that failed back on the queue, waiting longer after each failure.
+    fn retry(&mut self, mut queued: Queued) {
+        // the channel was rebuilt while it was being deleted, so it's queued already.
+        if self.keys.contains_key(&queued.deletion.message_id) {
+            return;
+        }
+        queued.failures += 1;
+        let backoff = RETRY_BACKOFF_SECS
+            .saturating_mul(1 << (queued.failures - 1).min(16))
+            .min(MAX_RETRY_BACKOFF_SECS);
+        self.enqueue(queued, core::time::Duration::from_secs(backoff));
+    }
+
+    /// Delete a batch of expired messages, channel by channel.  Deletions that fail go back on the
+    /// queue.
+    async fn reap(&mut self, due: Vec<Queued>) {
+        let mut by_channel: BTreeMap<ChannelId, Vec<Queued>> = BTreeMap::new();
+        for queued in due {
+            self.keys.remove(&queued.deletion.message_id);
+            by_channel
+                .entry(queued.deletion.channel_id)
+                .or_default()
+                .push(queued);
+        }
+
+        for (channel_id, messages) in by_channel {
+            let Some(mut channel) = self.channels.remove(&channel_id) else {
+                continue;
+            };
+            let failed = self.reap_channel(channel_id, &mut channel, messages).await;
+            self.channels.insert(channel_id, channel);
+            if !failed.is_empty() {
+                debug!(
+                    count = failed.len(),
+                    "Will retry expired messages in {}", channel_id
+                );
+            }
+            for queued in failed {
+                self.retry(queued);
+            }
+        }
+    }
+
+    /// Delete a channel's expired messages.  Returns the ones to try again later.
+    async fn reap_channel(
+        &self,
+        channel_id: ChannelId,
+        channel: &mut TtlChannel,
+        messages: Vec<Queued>,
+    ) -> Vec<Queued> {
+        self.open_run(channel_id, channel);
+        let mut run = SweepRun {
+            scanned: messages.len() as u32,
+            ..SweepRun::default()
+        };
+
+        // pins can change after a message arrives, so check them when it's due.  The bot's own
+        // notices are kept the same way.
+        let mut pinned: HashSet<MessageId> = match self.store.pins(channel_id).await {
+            Ok(pins) => pins.into_iter().collect(),
+            Err(e) => {
+                run.errors += 1;
+                abort(channel_id, &e);
+                self.record(channel_id, channel, run);
+                return messages;
+            }
+        };
+        match self.db.protected_messages(channel_id) {
+            Ok(protected) => pinned.extend(protected.into_iter().map(|(id, _)| id)),
+            Err(e) => {
+                error!(
+                    "Couldn't load protected messages for {}, not deleting: {e:#}",
+                    channel_id
+                );
+                run.errors += 1;
+                self.record(channel_id, channel, run);
+                return messages;
+            }
+        }
+        let (kept, messages): (Vec<Queued>, Vec<Queued>) = messages
+            .into_iter()
+            .partition(|m| pinned.contains(&m.deletion.message_id));
+        run.pinned = kept.len() as u32;
+
+        if channel.dry_run {
+            info!(
+                count = messages.len(),
+                "Dry run, not deleting expired messages in {}", channel_id
+            );
+            self.record(channel_id, channel, run);
+            return vec![];
+        }
+
+        let bulk_cutoff = Utc::now() - Duration::days(BULK_DELETE_MAX_AGE_DAYS)
+            + Duration::minutes(BULK_DELETE_SLACK_MINUTES);
+        let (old, recent): (Vec<Queued>, Vec<Queued>) = messages
+            .into_iter()
+            .partition(|m| m.deletion.sent_at < bulk_cutoff);
+        if !channel.delete_old && !old.is_empty() {
+            info!(
+                count = old.len(),
+                "Leaving expired messages in {} that are too old for bulk delete, \
+                 old_messages.delete is off",
+                channel_id
+            );
+        }
+
+        let mut failed = vec![];
+        let mut recent = recent.into_iter().peekable();
+        while recent.peek().is_some() {
+            let chunk: Vec<Queued> = recent.by_ref().take(100).collect();
+            let ids: Vec<MessageId> = chunk.iter().map(|m| m.deletion.message_id).collect();
+            match self.store.delete_messages(channel_id, &ids).await {
+                Ok(()) => {
+                    run.deleted += chunk.len() as u32;
+                    let deletions: Vec<Deletion> = chunk.into_iter().map(|m| m.deletion).collect();
+                    self.audit(channel, &deletions);
+                }
+                // one system message, or one that's already gone, fails the whole chunk.
+                Err(e) if e.policy() == ErrorPolicy::Skip => {
+                    debug!(
+                        "Couldn't bulk delete expired messages in {}, deleting them one at a time: {e}",
+                        channel_id
+                    );
+                    failed.extend(self.delete_each(channel_id, channel, chunk, &mut run).await);
+                }
+                Err(e) => {
+                    run.errors += 1;
+                    abort(channel_id, &e);
+                    failed.extend(chunk);
+                    failed.extend(recent);
+                    if channel.delete_old {
+                        failed.extend(old);
+                    }
+                    self.record(channel_id, channel, run);
+                    return failed;
+                }
+            }
+        }
+
+        if channel.delete_old {
+            let before = run.deleted;
+            failed.extend(self.delete_each(channel_id, channel, old, &mut run).await);
+            channel.stats.old_deleted += run.deleted - before;
+        }
+
+        debug!(
+            deleted = run.deleted,
+            pinned = run.pinned,
+            "Reaped expired messages in {}",
+            channel_id
+        );
+        self.record(channel_id, channel, run);
+        failed
+    }
+
+    /// Delete messages one at a time.  Ones nobody can delete are dropped.  Ones that fail for any
+    /// other reason are returned to be tried again, along with the rest once an error would fail
+    /// them too.
+    async fn delete_each(
+        &self,
+        channel_id: ChannelId,
+        channel: &TtlChannel,
+        messages: Vec<Queued>,
+        run: &mut SweepRun,
+    ) -> Vec<Queued> {
+        let mut deleted = vec![];
+        let mut failed = vec![];
+        let mut messages = messages.into_iter();
+        for queued in messages.by_ref() {
+            let message_id = queued.deletion.message_id;
+            match self.store.delete_message(channel_id, message_id).await {
+                Ok(()) => deleted.push(queued.deletion),
+                // already gone, or a system message nobody can delete.
+                Err(e) if e.policy() == ErrorPolicy::Skip => {
+                    debug!(%message_id, "Couldn't delete expired message, dropping it: {e}");
+                }
+                Err(e) if e.policy() == ErrorPolicy::Abort => {
+                    run.errors += 1;
+                    abort(channel_id, &e);
+                    failed.push(queued);
+                    break;
+                }
+                Err(e) => {
+                    warn!(%message_id, "Couldn't delete expired message, will retry: {e}");
+                    run.errors += 1;
+                    failed.push(queued);
+                }
+            }
+        }
+        failed.extend(messages);
+        run.deleted += deleted.len() as u32;
+        self.audit(channel, &deleted);
+        failed
+    }
+
+    fn audit(&self, channel: &TtlChannel, deletions: &[Deletion]) {
+        if deletions.is_empty() {
+            return;
+        }
+        if let Err(e) = self
+            .db
+            .record_deletions(channel.run_id, Utc::now(), deletions)
+        {
+            error!("Couldn't record {} deletions: {e:#}", deletions.len());
+        }
+    }
+
+    /// Make sure the channel has a sweep history record to add to.  A record covers an hour of
+    /// deletions, rather than one per batch.
+    fn open_run(&self, channel_id: ChannelId, channel: &mut TtlChannel) {
+        let now = Utc::now();
+        if channel.run_id.is_some()
+            && now - channel.run_started >= Duration::hours(RUN_RECORD_HOURS)
+        {
+            self.flush_run(channel_id, channel);
+        }
+        if channel.run_id.is_none() {
+            channel.run_started = now;
+            channel.run = SweepRun::default();
+            channel.run_id = match self.db.start_run(channel_id, now) {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    error!("Couldn't record sweep run: {e:#}");
+                    None
+                }
+            };
+            channel.stats.runs += 1;
+        }
+    }
+
+    /// Add a batch's results to the channel's stats and its current sweep history record.
+    fn record(&self, channel_id: ChannelId, channel: &mut TtlChannel, run: SweepRun) {
+        let now = Utc::now();
+        channel.run.scanned += run.scanned;
+        channel.run.deleted += run.deleted;
+        channel.run.pinned += run.pinned;
+        channel.run.errors += run.errors;
+        if let Some(run_id) = channel.run_id {
+            if let Err(e) = self.db.finish_run(run_id, now, &channel.run) {
+                error!("Couldn't record sweep run: {e:#}");
+            }
+        }
+
+        channel.stats.last_run = run.deleted;
+        channel.stats.all_runs += run.deleted;
+        if let Err(e) = channel.stats_tx.send(channel.stats.clone()) {
+            debug!("Nobody is watching stats for {}: {e}", channel_id);
+        }
+    }
+
+    /// Close the channel's current sweep history record.
+    fn flush_run(&self, channel_id: ChannelId, channel: &mut TtlChannel) {
+        if let Some(run_id) = channel.run_id.take() {
+            if let Err(e) = self.db.finish_run(run_id, Utc::now(), &channel.run) {
+                error!("Couldn't record sweep run for {}: {e:#}", channel_id);
+            }
+        }
+    }
+}
+
+/// Feed every message currently in the channel to the reaper, newest first, the same way the
+/// handler feeds it new ones.  Pinned messages are left out.
+async fn rebuild(
+    store: Arc<dyn MessageStore>,
+    channel_id: ChannelId,
+    tx: mpsc::UnboundedSender<TtlEvent>,
+) -> Result<(), DiscordError> {
+    let mut cursor: Option<MessageId> = None;
+    let mut queued = 0;
+    loop {
+        let page = store.messages(channel_id, cursor, 100).await?;
+        let Some(oldest) = page.iter().map(|m| m.id).min() else {
+            break;
+        };
+        cursor = Some(oldest);
+        for message in page.iter().filter(|m| !m.pinned) {
+            let event = TtlEvent::Message {
+                channel_id,
+                message_id: message.id,
+                author_id: message.author.id,
+                timestamp: *message.timestamp,
+            };
+            // the reaper has stopped.
+            if tx.send(event).is_err() {
+                return Ok(());
+            }
+            queued += 1;
+        }
+    }
+    info!(queued, "Rebuilt TTL queue for {}", channel_id);
+    Ok(())
+}
+
+/// Log an error that stops the channel's deletions until the next reap.
+fn abort(channel_id: ChannelId, error: &DiscordError) {
+    match error.is_misconfiguration() {
+        true => error!(
+            "Couldn't delete expired messages in {}: {error}. Give the bot Manage Messages there.",
+            channel_id
+        ),
+        false => error!(
+            "Couldn't delete expired messages in {}: {error}",
+            channel_id
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::store::fake::FakeStore;
+    use duration_string::DurationString;
+
+    const CHANNEL: ChannelId = ChannelId(2);
+
+    /// A reaper watching a channel with a day's TTL, which queues what's already in it.
+    async fn reaper(store: &Arc<FakeStore>) -> TtlReaper {
+        let ttl: DurationString = "1d".to_string().try_into().unwrap();
+        let mut channel = ChannelConfig::new(CHANNEL, ttl, false);
+        channel.ttl = Some(ttl);
+        let db = Arc::new(Database::open(":memory:").unwrap());
+        let (mut reaper, _) = TtlReaper::new(store.clone(), db);
+        let (stats_tx, _) = watch::channel(Stats::new(CHANNEL));
+        reaper
+            .handle(TtlEvent::Watch {
+                channel: Box::new(channel),
+                dry_run: false,
+                stats_tx,
+            })
+            .await;
+        let rebuild = &mut reaper.channels.get_mut(&CHANNEL).unwrap().rebuild;
+        rebuild.await.unwrap();
+        while let Ok(event) = reaper.rebuilt_rx.try_recv() {
+            reaper.handle(event).await;
+        }
+        reaper
+    }
+
+    /// Take everything that's due off the queue.
+    async fn due(reaper: &mut TtlReaper) -> Vec<Queued> {
+        let mut due = vec![reaper.queue.next().await.unwrap().into_inner()];
+        while let Some(Some(expired)) = reaper.queue.next().now_or_never() {
+            due.push(expired.into_inner());
+        }
+        due
+    }
+
+    #[tokio::test]
+    async fn failed_deletes_are_retried() {
+        let store = Arc::new(FakeStore::default());
+        let at = Utc::now() - Duration::days(20);
+        let ids: Vec<MessageId> = (0..3)
+            .map(|i| store.post(CHANNEL, 10, at, i, false))
+            .collect();
+        store.state().undeletable.insert(
+            ids[1],
+            DiscordError::Unavailable("discord returned 502".to_string()),
+        );
+        let mut reaper = reaper(&store).await;
+
+        let due = due(&mut reaper).await;
+        assert_eq!(due.len(), 3);
+        reaper.reap(due).await;
+
+        assert_eq!(store.remaining(CHANNEL), vec![ids[1]]);
+        let (_, key) = reaper.keys[&ids[1]];
+        assert!(
+            reaper.queue.deadline(&key)
+                >= tokio::time::Instant::now()
+                    + core::time::Duration::from_secs(RETRY_BACKOFF_SECS - 1)
+        );
+
+        // discord is back by the time it's tried again.
+        store.state().undeletable.clear();
+        let retry = reaper.queue.remove(&key).into_inner();
+        assert_eq!(retry.failures, 1);
+        reaper.keys.remove(&ids[1]);
+        reaper.reap(vec![retry]).await;
+        assert!(store.remaining(CHANNEL).is_empty());
+        assert!(reaper.queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn undeletable_messages_are_dropped_alone() {
+        let store = Arc::new(FakeStore::default());
+        let at = Utc::now() - Duration::days(2);
+        let ids: Vec<MessageId> = (0..3)
+            .map(|i| store.post(CHANNEL, 10, at, i, false))
+            .collect();
+        store
+            .state()
+            .undeletable
+            .insert(ids[1], DiscordError::SystemMessage);
+        let mut reaper = reaper(&store).await;
+
+        let due = due(&mut reaper).await;
+        reaper.reap(due).await;
+
+        assert_eq!(store.remaining(CHANNEL), vec![ids[1]]);
+        assert!(reaper.queue.is_empty());
+        assert!(reaper.keys.is_empty());
+    }
+}